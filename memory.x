@@ -1,5 +1,24 @@
 MEMORY
 {
     FLASH : ORIGIN = 0x08000000, LENGTH = 512K 
-    RAM : ORIGIN = 0x20000000, LENGTH = 128K
-}
\ No newline at end of file
+    /* Kernel memory, only accessible in privileged mode. */
+    RAM : ORIGIN = 0x20000000, LENGTH = 64K
+    /* Memory for tasks, e.g. their stacks. Must be aligned to its size for the MPU. */
+    APP_RAM : ORIGIN = 0x20010000, LENGTH = 64K
+}
+
+__flash_start = ORIGIN(FLASH);
+__flash_end = ORIGIN(FLASH) + LENGTH(FLASH);
+__app_ram_start = ORIGIN(APP_RAM);
+__app_ram_end = ORIGIN(APP_RAM) + LENGTH(APP_RAM);
+
+/* Statics placed with `#[link_section = ".app_ram"]`. Not initialized on reset.
+   A section of its own in APP_RAM, not inserted among the sections of cortex-m-rt, so it does not
+   move the location counter cortex-m-rt uses for the end of the stack. */
+SECTIONS
+{
+    .app_ram (NOLOAD) : ALIGN(8)
+    {
+        *(.app_ram .app_ram.*);
+    } > APP_RAM
+}
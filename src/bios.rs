@@ -4,53 +4,128 @@ use core::fmt::{Error, Write};
 use cortex_m::asm::bkpt;
 use cortex_m::peripheral::NVIC;
 use stm32f4xx_hal::pac::{Interrupt, USART2};
+use stm32f4xx_hal::pac::usart1::RegisterBlock;
 use stm32f4xx_hal::serial::{CFlag, Flag};
-use stm32f4xx_hal::uart::{TxISR, Event};
+use stm32f4xx_hal::uart::{RxISR, TxISR, Event};
 use stm32f4xx_hal::{ClearFlags, interrupt, ReadFlags};
+use stm32f4xx_hal::hal_02::serial::Read as R;
 use stm32f4xx_hal::hal_02::serial::Write as W;
 use stm32f4xx_hal::Listen;
+use crate::{console, task};
+use crate::task::WaitReason;
 
-type FIFO = crate::fifo::FIFO<u8, 128>;
+/// Size of the transmit buffer in bytes.
+const TX_BUFFER_SIZE: usize = 128;
+/// Tasks blocked on a full transmit buffer are woken once this many bytes are free again.
+const TX_WAKE_THRESHOLD: usize = TX_BUFFER_SIZE / 2;
+
+type FIFO = crate::fifo::FIFO<u8, TX_BUFFER_SIZE>;
+type InputFIFO = crate::fifo::FIFO<u8, 64>;
 type Serial = stm32f4xx_hal::serial::Serial<USART2>;
 
 static mut SERIAL: Option<Serial> = None;
 static mut TX_BUFFER: FIFO = FIFO::new_with(0u8);
+static mut RX_BUFFER: InputFIFO = InputFIFO::new_with(0u8);
+
+/// Priority of the USART2 interrupt, the same as PendSV. The interrupt wakes tasks and reschedules,
+/// so it must not interrupt the kernel exceptions while they change the task table.
+const INTERRUPT_PRIORITY: u8 = 15;
 
-pub fn initialize(mut serial: Serial) {
+pub fn initialize(mut serial: Serial, nvic: &mut NVIC) {
     unsafe {
         // Wait for completion of any previous transmissions before enabling interrupt.
         while !serial.is_tx_empty() {}
 
-        // Clear pending flag to not trigger immediately and enable interrupt.
+        // Clear pending flag to not trigger immediately, set priority and enable interrupt.
         NVIC::unpend(Interrupt::USART2);
+        nvic.set_priority(Interrupt::USART2, INTERRUPT_PRIORITY);
         NVIC::unmask(Interrupt::USART2);
 
         // Hand over to BIOS and start listening;
         SERIAL = Some(serial);
         enable_tx_interrupt();
+        enable_rx_interrupt();
     }
 }
 
-pub unsafe fn take_serial() -> Option<Serial> {
-    SERIAL.take()
-}
-
 /// Raw, unbuffered access to output.
+/// Polls the transmit register of USART2 with interrupts masked instead of relying on the USART2
+/// interrupt, so it can be used while handling a panic or fault. Bytes still waiting in the
+/// transmit buffer are sent first to keep the output in order.
+/// Works before and after [initialize], but output is dropped until USART2 has been enabled.
 pub struct RawOutput;
 
+pub fn raw_output() -> RawOutput { RawOutput }
+
+impl RawOutput {
+    fn usart() -> &'static RegisterBlock {
+        unsafe { &*USART2::ptr() }
+    }
+
+    /// Wait until the transmit register is empty and send `byte`.
+    fn send(&mut self, byte: u8) {
+        let usart = Self::usart();
+        while usart.sr.read().txe().bit_is_clear() {}
+        usart.dr.write(|w| w.dr().bits(byte as u16));
+    }
+}
+
+impl Write for RawOutput {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        if Self::usart().cr1.read().ue().bit_is_clear() {
+            return Ok(());
+        }
+        cortex_m::interrupt::free(|_| {
+            // The transmit interrupt finds the buffer empty afterwards and only clears its flag.
+            let fifo = unsafe { get_raw_tx_buffer() };
+            while let Some(byte) = fifo.pop_front() {
+                self.send(byte);
+            }
+            for byte in string.bytes() {
+                self.send(byte);
+            }
+        });
+        Ok(())
+    }
+}
+
 
 /// Buffered output with interrupt.
 pub struct BufferedOutput;
 
 pub fn buffered_output() -> BufferedOutput { BufferedOutput }
 
+/// Buffered input filled by the receive interrupt.
+pub struct BufferedInput;
+
+pub fn buffered_input() -> BufferedInput { BufferedInput }
+
+impl BufferedInput {
+    /// Copy received bytes into `buffer`.
+    /// Returns number of bytes read, which is zero if nothing has been received.
+    pub unsafe fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let fifo = get_raw_rx_buffer();
+
+        // We need to disable our interrupt to safely access the queue.
+        mask_interrupt();
+        let count = fifo.pop_into(buffer);
+        unmask_interrupt();
+        count
+    }
+}
+
 impl BufferedOutput {
+    /// Number of bytes that can be appended without dropping any.
+    pub fn free_space(&self) -> usize {
+        unsafe { get_raw_tx_buffer().free_space() }
+    }
+
     pub unsafe fn append(&mut self, bytes: &[u8]) -> Result<usize, usize> {
         let tx = get_raw_serial();
         let fifo = get_raw_tx_buffer();
 
         // We need to disable our interrupt to safely access the queue.
-        disable_tx_interrupt();
+        mask_interrupt();
         // Await last transmission.
         while !tx.is_tx_empty() {}
 
@@ -59,7 +134,7 @@ impl BufferedOutput {
 
         // Start transmission and re-enable interrupt.
         send_next(tx);
-        enable_tx_interrupt();
+        unmask_interrupt();
         result
     }
 }
@@ -71,7 +146,7 @@ impl Write for BufferedOutput {
             let fifo = get_raw_tx_buffer();
 
             // We need to disable our interrupt to safely access the queue.
-            disable_tx_interrupt();
+            mask_interrupt();
             // Await last transmission.
             while !tx.is_tx_empty() {}
             for character in string.bytes() {
@@ -82,7 +157,7 @@ impl Write for BufferedOutput {
 
             // Start transmission and re-enable interrupt.
             send_next(tx);
-            enable_tx_interrupt();
+            unmask_interrupt();
             Ok(())
         }
     }
@@ -96,27 +171,61 @@ unsafe fn get_raw_tx_buffer() -> &'static mut FIFO {
     &mut TX_BUFFER
 }
 
-
-/// Helper function to disable the transmission interrupt before a critical section.
-unsafe fn disable_tx_interrupt() {
-    let serial = get_raw_serial();
-    serial.unlisten(Event::TransmissionComplete);
+unsafe fn get_raw_rx_buffer() -> &'static mut InputFIFO {
+    &mut RX_BUFFER
 }
 
-/// Helper function to enable the transmission interrupt after a critical section.
+
+/// Helper function to enable the transmission interrupt.
 unsafe fn enable_tx_interrupt() {
     let serial = get_raw_serial();
     serial.listen(Event::TransmissionComplete);
 }
 
+/// Helper function to enable the receive interrupt.
+unsafe fn enable_rx_interrupt() {
+    let serial = get_raw_serial();
+    serial.listen(Event::RxNotEmpty);
+}
+
+/// Helper function to keep the USART2 interrupt from firing during a critical section.
+/// The handler serves both buffers whatever event triggered it, so disabling only one event in the
+/// USART would still let the other one in. Events arriving meanwhile stay pending.
+unsafe fn mask_interrupt() {
+    NVIC::mask(Interrupt::USART2);
+}
+
+/// Helper function to let the USART2 interrupt fire again after a critical section.
+unsafe fn unmask_interrupt() {
+    NVIC::unmask(Interrupt::USART2);
+}
+
 #[interrupt]
 unsafe fn USART2() {
+    let rx = get_raw_serial();
+    if rx.is_rx_not_empty() {
+        // Reading the data register clears the interrupt, even if an overrun occurred.
+        // Bytes that do not fit into the buffer are dropped.
+        // Commands for the kernel console are not passed to tasks.
+        if let Ok(byte) = rx.read() {
+            if !console::receive(byte) {
+                get_raw_rx_buffer().push_back(byte);
+            }
+        }
+    }
+
     let tx = get_raw_serial();
     if tx.is_tx_empty() {
         let fifo = get_raw_tx_buffer();
         if let Some(byte) = fifo.pop_front() {
             // Writing new data clears the transmission complete interrupt.
             tx.write(byte).unwrap();
+            if fifo.free_space() >= TX_WAKE_THRESHOLD {
+                // Reschedules right away if a woken writer has a higher priority than the current
+                // task. This includes the idle task, so suppressed ticks are resumed as well.
+                // Writers of the same priority as a busy task take turns on the next tick.
+                task::wake_tasks(WaitReason::TxSpace);
+            }
         } else {
             // Clear transmission complete flag to stop interrupt from triggering.
             tx.clear_flags(CFlag::TransmissionComplete);
@@ -0,0 +1,100 @@
+//! Kernel console on the UART.
+//!
+//! Received lines starting with `!` are taken by the kernel as commands instead of being passed to
+//! tasks, e.g. `!stack` lists the peak stack usage of all tasks. Commands run from `SysTick` and
+//! write their output to the kernel log, so it is paced like any other kernel message.
+
+use crate::task::TASK_TABLE;
+use crate::time;
+
+/// Marks a line as command if it is the first byte of the line.
+const PREFIX: u8 = b'!';
+/// Maximum length of a command in bytes. Longer commands are cut off.
+const LINE_SIZE: usize = 16;
+
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(),
+}
+
+const COMMANDS: [Command; 2] = [
+    Command { name: "help", help: "list commands", run: help },
+    Command { name: "stack", help: "peak stack usage of all tasks", run: stack },
+];
+
+/// Where the receive interrupt is within the current line.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Nothing received since the last line break.
+    LineStart,
+    /// Line is passed to tasks.
+    Passing,
+    /// Line is a command that is being received.
+    Collecting,
+    /// Command is complete and waits for [poll]. Further input is passed to tasks until then,
+    /// except for line breaks, which may follow the one ending the command, e.g. in `\r\n`.
+    Ready,
+}
+
+static mut STATE: State = State::LineStart;
+static mut LINE: [u8; LINE_SIZE] = [0; LINE_SIZE];
+static mut LENGTH: usize = 0;
+
+/// Called by the receive interrupt for each received byte.
+/// Returns whether the byte belongs to a command, so it must not be passed to tasks.
+pub unsafe fn receive(byte: u8) -> bool {
+    let end_of_line = byte == b'\r' || byte == b'\n';
+    match STATE {
+        State::LineStart if byte == PREFIX => {
+            LENGTH = 0;
+            STATE = State::Collecting;
+            true
+        }
+        State::LineStart | State::Passing => {
+            STATE = if end_of_line { State::LineStart } else { State::Passing };
+            false
+        }
+        State::Collecting if end_of_line => {
+            STATE = State::Ready;
+            // Commands run on the next tick, which may be far away while idle.
+            time::wake_from_idle();
+            true
+        }
+        State::Collecting => {
+            if LENGTH < LINE_SIZE {
+                LINE[LENGTH] = byte;
+                LENGTH += 1;
+            }
+            true
+        }
+        State::Ready => end_of_line,
+    }
+}
+
+/// Run a completely received command.
+pub unsafe fn poll() {
+    if STATE != State::Ready {
+        return;
+    }
+    // The receive interrupt leaves the line alone until the state changes.
+    let name = core::str::from_utf8(&LINE[..LENGTH]).unwrap_or("").trim();
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(),
+        None => log!(Warn, "unknown command '{}', try !help", name),
+    }
+    STATE = State::LineStart;
+}
+
+fn help() {
+    for command in COMMANDS.iter() {
+        log!(Info, "!{}: {}", command.name, command.help);
+    }
+}
+
+fn stack() {
+    for task in unsafe { TASK_TABLE.tasks() } {
+        let state = if task.is_exited() { " (exited)" } else { "" };
+        log!(Info, "task {}: {} of {} stack bytes used{}", task.id(), task.stack_peak(), task.stack().len(), state);
+    }
+}
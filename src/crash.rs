@@ -0,0 +1,156 @@
+//! Crash records kept in RAM across a system reset.
+//!
+//! Instead of waiting for a debugger, fatal faults store what is known about the crash in the
+//! `.uninit` section, which is neither zeroed nor initialized on startup, and reset the system.
+//! On the next boot, [take_previous] checks for a valid record, so it can be printed and read by
+//! tasks with [crate::syscalls::stubs::read_crash_report].
+
+use core::fmt::{self, Display, Formatter, Write};
+use core::mem::{offset_of, MaybeUninit};
+use cortex_m::peripheral::{DCB, SCB};
+use cortex_m_rt::ExceptionFrame;
+use crate::task::OS_CURRENT_TASK;
+use crate::{log, time};
+
+/// Marks a valid record. Anything else is left over from power-on or an earlier boot.
+const MAGIC: u32 = 0xdead_c0de;
+/// Number of kernel log records kept with the crash record.
+const LOG_LINES: usize = 4;
+/// Maximum length of a kept log record in bytes.
+const LOG_LINE_SIZE: usize = 64;
+/// Task ID used if the crash happened before the scheduler was started.
+const NO_TASK: u32 = u32::MAX;
+
+/// What is known about a fatal fault.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct CrashRecord {
+    magic: u32,
+    /// r0-r3, r12, lr, pc and xPSR stacked on exception entry.
+    frame: [u32; 8],
+    cfsr: u32,
+    hfsr: u32,
+    mmfar: u32,
+    bfar: u32,
+    task: u32,
+    /// Ticks since the scheduler was started.
+    uptime: u64,
+    log_lengths: [u32; LOG_LINES],
+    log: [[u8; LOG_LINE_SIZE]; LOG_LINES],
+    /// Sum of all words before it, so a record torn by a reset during writing is ignored.
+    checksum: u32,
+}
+
+impl CrashRecord {
+    fn checksum(&self) -> u32 {
+        let words = offset_of!(Self, checksum) / 4;
+        let words = unsafe { core::slice::from_raw_parts(self as *const Self as *const u32, words) };
+        words.iter().fold(0u32, |sum, word| sum.wrapping_add(*word))
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC && self.checksum == self.checksum()
+    }
+}
+
+impl Display for CrashRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let [r0, r1, r2, r3, r12, lr, pc, xpsr] = self.frame;
+        write!(f, "Crash at tick {}", self.uptime)?;
+        if self.task != NO_TASK {
+            write!(f, " in task {}", self.task)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "pc={:#010x} lr={:#010x} xpsr={:#010x}", pc, lr, xpsr)?;
+        writeln!(f, "r0={:#010x} r1={:#010x} r2={:#010x} r3={:#010x} r12={:#010x}", r0, r1, r2, r3, r12)?;
+        writeln!(f, "CFSR={:#010x} HFSR={:#010x} MMFAR={:#010x} BFAR={:#010x}", self.cfsr, self.hfsr, self.mmfar, self.bfar)?;
+        for (line, length) in self.log.iter().zip(self.log_lengths) {
+            let Some(text) = line.get(..length as usize).filter(|text| !text.is_empty()) else {
+                continue;
+            };
+            writeln!(f, "> {}", core::str::from_utf8(text).unwrap_or("?"))?;
+        }
+        Ok(())
+    }
+}
+
+#[link_section = ".uninit.CRASH_RECORD"]
+static mut CRASH_RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+/// Record found on boot.
+static mut PREVIOUS_CRASH: Option<CrashRecord> = None;
+
+/// Store a crash record for the fault described by `frame` and reset the system.
+/// If a debugger is attached, it gets a chance to inspect the fault first.
+pub unsafe fn record_and_reset(frame: &ExceptionFrame) -> ! {
+    let scb = &*SCB::PTR;
+    let mut record = CrashRecord {
+        magic: MAGIC,
+        frame: [frame.r0(), frame.r1(), frame.r2(), frame.r3(), frame.r12(), frame.lr(), frame.pc(), frame.xpsr()],
+        cfsr: scb.cfsr.read(),
+        hfsr: scb.hfsr.read(),
+        mmfar: scb.mmfar.read(),
+        bfar: scb.bfar.read(),
+        task: if OS_CURRENT_TASK.is_null() { NO_TASK } else { (*OS_CURRENT_TASK).id() },
+        uptime: time::now(),
+        log_lengths: [0; LOG_LINES],
+        log: [[0; LOG_LINE_SIZE]; LOG_LINES],
+        checksum: 0,
+    };
+    let first = log::next_sequence().saturating_sub(LOG_LINES as u32);
+    for (index, sequence) in (first..log::next_sequence()).enumerate() {
+        if let Some(entry) = log::read(sequence) {
+            let length = entry.text().len().min(LOG_LINE_SIZE);
+            record.log[index][..length].copy_from_slice(&entry.text()[..length]);
+            record.log_lengths[index] = length as u32;
+        }
+    }
+    record.checksum = record.checksum();
+    CRASH_RECORD.write(record);
+
+    if DCB::is_debugger_attached() {
+        cortex_m::asm::bkpt();
+    }
+    SCB::sys_reset()
+}
+
+/// Check for a record left by a crash before the last reset and invalidate it, so it is only
+/// reported once. Must be called once on boot.
+pub unsafe fn take_previous() -> Option<&'static CrashRecord> {
+    let record = CRASH_RECORD.assume_init_mut();
+    if record.is_valid() {
+        PREVIOUS_CRASH = Some(*record);
+    }
+    record.magic = 0;
+    previous()
+}
+
+/// Record of the crash before the last reset, if any.
+pub fn previous() -> Option<&'static CrashRecord> {
+    unsafe { PREVIOUS_CRASH.as_ref() }
+}
+
+/// Formats into a byte buffer, cutting off what does not fit.
+pub struct BufferWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> BufferWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, length: 0 }
+    }
+
+    /// Number of bytes written.
+    pub fn len(&self) -> usize {
+        self.length
+    }
+}
+
+impl Write for BufferWriter<'_> {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        let count = string.len().min(self.buffer.len() - self.length);
+        self.buffer[self.length..self.length + count].copy_from_slice(&string.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
@@ -1,8 +1,30 @@
-use cortex_m_rt::{exception, ExceptionFrame};
-use crate::task::{OS_CURRENT_TASK, OS_NEXT_TASK};
-use core::fmt::Write;
-use cortex_m::asm::bkpt;
-use crate::bios;
+use crate::task::{self, Task, OS_CURRENT_TASK, OS_NEXT_TASK, TASK_TABLE};
+use crate::{idle, mpu};
+
+/// Save s16-s31 below r0 if the interrupted task has an active floating point context.
+/// Bit 4 of EXC_RETURN is cleared if the hardware stacked an extended frame. Executing `vstmdb`
+/// also triggers the lazy stacking of s0-s15 reserved in that frame.
+#[cfg(target_abi = "eabihf")]
+macro_rules! save_fp_context {
+    () => { "tst lr, #0x10\n it eq\n vstmdbeq r0!, {{s16-s31}}" };
+}
+
+/// Restore s16-s31 from r0 if the next task has an active floating point context.
+#[cfg(target_abi = "eabihf")]
+macro_rules! restore_fp_context {
+    () => { "tst lr, #0x10\n it eq\n vldmiaeq r0!, {{s16-s31}}" };
+}
+
+/// Without hardware floating point, tasks can never have a floating point context.
+#[cfg(not(target_abi = "eabihf"))]
+macro_rules! save_fp_context {
+    () => { "" };
+}
+
+#[cfg(not(target_abi = "eabihf"))]
+macro_rules! restore_fp_context {
+    () => { "" };
+}
 
 #[naked]
 #[no_mangle]
@@ -10,51 +32,96 @@ use crate::bios;
 fn PendSV() {
     unsafe {
         core::arch::asm!(
-        // 1. Save r4-r11
-        "push {{r4-r11}}",
+        // Tasks run in thread mode on the process stack, so the hardware has already stacked
+        // r0-r3, r12, lr, pc and xPSR (and s0-s15 and FPSCR if floating point was used) onto the
+        // PSP. We are running on the MSP here.
 
-        // 2. Save stack pointer to task control block
-        "ldr r0, ={0}", // Load address of OS_CURRENT_TASK into r0
-        "ldr r0, [r0]", // Load contents of OS_CURRENT_TASK into r0
-        "str SP, [r0]", // Store stack pointer into TSB
+        // 1. Save s16-s31 (if needed) and r4-r11 on the process stack of the current task, unless
+        // check_context returns the stack pointer to store instead because they do not fit.
+        // It only clobbers r0-r3, r12 and lr, so keep EXC_RETURN on the main stack.
+        "mov r0, lr",
+        "push {{r0, lr}}",
+        "bl {3}",
+        "pop {{r1, lr}}",
+        "cbnz r0, 1f",
+        "mrs r0, PSP",
+        save_fp_context!(),
+        "stmdb r0!, {{r4-r11}}",
+        "1:",
 
-        // 3. Load next stack pointer from next TSB
-        "ldr r0, ={1}", // Load address of OS_NEXT_TASK into r0
-        "ldr r0, [r0]", // Load contents of OS_NEXT_TASK into r0
-        "ldr SP, [r0]", // Store stack pointer into TSB
+        // 2. Save process stack pointer and EXC_RETURN to task control block
+        "ldr r1, ={0}", // Load address of OS_CURRENT_TASK into r1
+        "ldr r2, [r1]", // Load contents of OS_CURRENT_TASK into r2
+        "str r0, [r2]", // Store stack pointer into TSB
+        "str lr, [r2, #4]", // Store EXC_RETURN into TSB
 
-        // 3.1 Force Cache Flush? After stack change.
+        // 3. Load next stack pointer and EXC_RETURN from next TSB and make it the current task
+        "mov r0, r2", // Keep previous task as first argument of before_switch
+        "ldr r3, ={1}", // Load address of OS_NEXT_TASK into r3
+        "ldr r2, [r3]", // Load contents of OS_NEXT_TASK into r2
+        "str r2, [r1]", // OS_CURRENT_TASK = OS_NEXT_TASK
+
+        // 3.1 Check previous and prepare next task. Clobbers r0-r3 and lr and may change
+        // OS_CURRENT_TASK, so reload its TSB afterwards.
+        "mov r1, r2",
+        "bl {2}",
+        "ldr r1, ={0}", // Load address of OS_CURRENT_TASK into r1
+        "ldr r2, [r1]", // Load contents of OS_CURRENT_TASK into r2
+        "ldr r0, [r2]", // Load stack pointer from TSB
+        "ldr lr, [r2, #4]", // Load EXC_RETURN from TSB
+
+        // 4. Restore r4-r11 and s16-s31 (if needed) and switch process stack
+        "ldmia r0!, {{r4-r11}}",
+        restore_fp_context!(),
+        "msr PSP, r0",
+
+        // 4.1 Force Cache Flush? After stack and memory region change.
         "isb",
         "dsb",
 
-        // 4. Restore r4-r11
-        "pop {{r4-r11}}",
-
-        // 5. Return to mode we came from.
+        // 5. Return to mode we came from. EXC_RETURN tells the hardware whether to unstack an
+        // extended frame.
         "bx lr",
         sym OS_CURRENT_TASK,
         sym OS_NEXT_TASK,
+        sym before_switch,
+        sym check_context,
         options(noreturn),
         )
     };
 }
 
+/// Called by [PendSV] before it saves the context of the current task on its process stack.
+/// Returns zero if the context fits into the task's stack. Otherwise, nothing may be written, since the
+/// kernel would overwrite whatever lies below the stack, e.g. the main stack. The stack pointer the
+/// task would have had is returned instead, which [Task::has_overflowed] detects. Exited tasks never
+/// resume, so their context is not saved either.
+unsafe extern "C" fn check_context(exc_return: u32) -> usize {
+    let task = &*OS_CURRENT_TASK;
+    let stack_pointer = cortex_m::register::psp::read() as usize;
+    // r4-r11, and s16-s31 if bit 4 of EXC_RETURN is cleared.
+    let size = if exc_return & 0x10 == 0 { 96 } else { 32 };
+    let stack = task.stack();
+    if !task.is_exited() && stack_pointer <= stack.end && stack_pointer >= stack.start + 4 + size {
+        return 0;
+    }
+    stack_pointer.wrapping_sub(size).max(1)
+}
 
-#[exception]
-unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
-    let core_peripherals = cortex_m::peripheral::Peripherals::steal();
-    let cfsr = core_peripherals.SCB.cfsr.read();
-    let usage_fault = (cfsr >> 16) as u16;
-    let bus_fault = ((cfsr >> 8) & 0xff) as u8;
-    let memory_fault = cfsr as u8;
-
-
-    let mut serial = bios::take_serial().expect("cannot print before serial is configured");
-    writeln!(serial, "Hard Fault {:?}", frame).unwrap();
-    writeln!(serial, "UFSR={:#016b}", usage_fault).unwrap();
-    writeln!(serial, "BFSR={:#08b}", bus_fault).unwrap();
-    writeln!(serial, "MMFSR={:#08b}", memory_fault).unwrap();
-
-    // Recovery is highly unlikely, so we simply wait for a manual reset and allow debugging.
-    loop { bkpt() }
+/// Called by [PendSV] with the task it switched away from and the task it is about to switch to.
+/// The previous task is ended if it overflowed its stack, or started over if it is the idle task, which
+/// must always be ready. Both may select a different next task.
+unsafe extern "C" fn before_switch(previous: *mut Task, next: *mut Task) {
+    if !(*previous).is_exited() && (*previous).has_overflowed() {
+        task::handle_stack_overflow(previous);
+    }
+    if (*previous).is_exited() && TASK_TABLE.is_idle_task(previous) {
+        idle::restart(&mut *previous);
+    }
+    let next = task::replace_next_task(next);
+    task::account_switch(previous, next);
+    idle::prepare_ticks(next);
+    mpu::load_task_regions((*next).regions());
+    #[cfg(feature = "stack-guard")]
+    mpu::load_stack_guard((*next).stack());
 }
@@ -0,0 +1,207 @@
+//! Fault handlers with decoded reports.
+//!
+//! MemManage, BusFault and UsageFault are enabled, so faults caused by a task end up in their own
+//! handler instead of escalating to HardFault. Such a fault only ends the offending task and is
+//! reported in the kernel log, while the rest of the system keeps running. Faults of the kernel
+//! itself are fatal: they are printed and recorded by [crash::record_and_reset].
+
+use core::fmt::Write;
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::{exception, ExceptionFrame};
+use crate::task::{self, OS_CURRENT_TASK};
+use crate::{bios, crash};
+
+/// Enable bits of the MemManage, BusFault and UsageFault handlers in SHCSR.
+const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+const SHCSR_BUSFAULTENA: u32 = 1 << 17;
+const SHCSR_USGFAULTENA: u32 = 1 << 18;
+/// Trap integer division by zero instead of returning zero.
+const CCR_DIV_0_TRP: u32 = 1 << 4;
+
+/// CFSR bit indicating that MMFAR holds the faulting address.
+const CFSR_MMARVALID: u32 = 1 << 7;
+/// CFSR bit indicating that BFAR holds the faulting address.
+const CFSR_BFARVALID: u32 = 1 << 15;
+/// CFSR bits indicating that the exception frame could not be stacked, so it must not be read.
+const CFSR_STACKING_ERRORS: u32 = 1 << 4 | 1 << 12;
+
+/// Set bits of CFSR with name and description. Bits for valid addresses are reported separately.
+const CFSR_REASONS: [(u32, &str, &str); 17] = [
+    (1 << 0, "IACCVIOL", "instruction fetch from memory the task may not execute"),
+    (1 << 1, "DACCVIOL", "data access to memory the task may not access"),
+    (1 << 3, "MUNSTKERR", "memory protection fault on exception return"),
+    (1 << 4, "MSTKERR", "memory protection fault on exception entry"),
+    (1 << 5, "MLSPERR", "memory protection fault during lazy floating point state preservation"),
+    (1 << 8, "IBUSERR", "bus error on instruction fetch"),
+    (1 << 9, "PRECISERR", "precise data bus error"),
+    (1 << 10, "IMPRECISERR", "imprecise data bus error, address unknown"),
+    (1 << 11, "UNSTKERR", "bus error on exception return"),
+    (1 << 12, "STKERR", "bus error on exception entry"),
+    (1 << 13, "LSPERR", "bus error during lazy floating point state preservation"),
+    (1 << 16, "UNDEFINSTR", "undefined instruction"),
+    (1 << 17, "INVSTATE", "invalid execution state, e.g. branch to an ARM address"),
+    (1 << 18, "INVPC", "invalid EXC_RETURN on exception return"),
+    (1 << 19, "NOCP", "coprocessor access, e.g. floating point with FPU disabled"),
+    (1 << 24, "UNALIGNED", "unaligned memory access"),
+    (1 << 25, "DIVBYZERO", "integer division by zero"),
+];
+
+/// Set bits of HFSR with name and description.
+const HFSR_REASONS: [(u32, &str, &str); 3] = [
+    (1 << 1, "VECTTBL", "bus error reading the vector table"),
+    (1 << 30, "FORCED", "escalated from a configurable fault"),
+    (1 << 31, "DEBUGEVT", "debug event without a debugger"),
+];
+
+/// Configurable fault, passed from the entry points to [handle_fault].
+#[derive(Debug, Clone, Copy)]
+#[repr(u32)]
+enum Fault {
+    MemManage = 0,
+    Bus,
+    Usage,
+}
+
+impl Fault {
+    /// Name of the exception as used by ARM.
+    fn name(self) -> &'static str {
+        match self {
+            Fault::MemManage => "MemManage",
+            Fault::Bus => "BusFault",
+            Fault::Usage => "UsageFault",
+        }
+    }
+}
+
+/// Enable the configurable fault handlers and trap division by zero.
+pub fn initialize(scb: &mut SCB) {
+    unsafe {
+        scb.shcsr.modify(|shcsr| shcsr | SHCSR_MEMFAULTENA | SHCSR_BUSFAULTENA | SHCSR_USGFAULTENA);
+        scb.ccr.modify(|ccr| ccr | CCR_DIV_0_TRP);
+    }
+}
+
+/// Entry points pass the kind of fault, EXC_RETURN and the main stack pointer on entry, like
+/// [crate::syscalls] does for `SVCall`. [handle_fault] returns directly to the interrupted code.
+macro_rules! fault_entry {
+    ($name:ident, $fault:expr) => {
+        #[naked]
+        #[no_mangle]
+        #[allow(non_snake_case)]
+        unsafe fn $name() {
+            core::arch::asm!(
+            "movs r0, #{fault}",
+            "mov r1, lr",
+            "mrs r2, MSP",
+            "b {handler}",
+            fault = const $fault as u32,
+            handler = sym handle_fault,
+            options(noreturn)
+            )
+        }
+    };
+}
+
+fault_entry!(MemoryManagement, Fault::MemManage);
+fault_entry!(BusFault, Fault::Bus);
+fault_entry!(UsageFault, Fault::Usage);
+
+/// End the faulting task, or report and record the crash if the kernel itself faulted.
+unsafe extern "C" fn handle_fault(fault: Fault, exc_return: u32, main_stack_pointer: *const u32) {
+    let scb = &*SCB::PTR;
+    let cfsr = scb.cfsr.read();
+    // Thread mode (bit 3) on the process stack (bit 2) means a task was running.
+    let from_task = exc_return & 0b1100 == 0b1100 && !OS_CURRENT_TASK.is_null();
+    let stack_pointer = if exc_return & 0b100 != 0 {
+        cortex_m::register::psp::read() as *const ExceptionFrame
+    } else {
+        main_stack_pointer as *const ExceptionFrame
+    };
+
+    if !from_task {
+        let mut output = bios::raw_output();
+        writeln!(output, "{} in kernel", fault.name()).ok();
+        report_cfsr(&mut output, cfsr).ok();
+        crash::record_and_reset(&*stack_pointer);
+    }
+
+    let id = (*OS_CURRENT_TASK).id();
+    if hit_stack_guard(cfsr) {
+        log!(Error, "{} in task {}, stack overflow", fault.name(), id);
+    } else if cfsr & CFSR_STACKING_ERRORS != 0 {
+        log!(Error, "{} in task {}, stack overflow?", fault.name(), id);
+    } else {
+        log!(Error, "{} in task {} at pc={:#010x}", fault.name(), id, (*stack_pointer).pc());
+    }
+    for (_, name, description) in reasons(cfsr, &CFSR_REASONS) {
+        log!(Error, "  {}: {}", name, description);
+    }
+    if let Some(address) = fault_address(cfsr) {
+        log!(Error, "  address {:#010x}", address);
+    }
+
+    // Status bits are cleared by writing ones.
+    scb.cfsr.write(cfsr);
+    task::exit_current_task(task::EXIT_CODE_FAULT);
+}
+
+#[exception]
+unsafe fn HardFault(frame: &ExceptionFrame) -> ! {
+    let scb = &*SCB::PTR;
+    let hfsr = scb.hfsr.read();
+    let cfsr = scb.cfsr.read();
+
+    let mut output = bios::raw_output();
+    writeln!(output, "Hard Fault {:?}", frame).ok();
+    report(&mut output, hfsr, &HFSR_REASONS).ok();
+    report_cfsr(&mut output, cfsr).ok();
+
+    // Recovery is highly unlikely, so keep what we know for the next boot and start over.
+    crash::record_and_reset(frame)
+}
+
+/// Entries of `table` whose bit is set in `status`.
+fn reasons(status: u32, table: &'static [(u32, &'static str, &'static str)]) -> impl Iterator<Item = &'static (u32, &'static str, &'static str)> {
+    table.iter().filter(move |(bit, _, _)| status & bit != 0)
+}
+
+/// Faulting address, if CFSR marks MMFAR or BFAR as valid.
+fn fault_address(cfsr: u32) -> Option<u32> {
+    let scb = unsafe { &*SCB::PTR };
+    if cfsr & CFSR_MMARVALID != 0 {
+        Some(scb.mmfar.read())
+    } else if cfsr & CFSR_BFARVALID != 0 {
+        Some(scb.bfar.read())
+    } else {
+        None
+    }
+}
+
+/// Whether the current task faulted by accessing the guard at the bottom of its stack.
+#[cfg(feature = "stack-guard")]
+unsafe fn hit_stack_guard(cfsr: u32) -> bool {
+    let guard = crate::mpu::stack_guard((*OS_CURRENT_TASK).stack());
+    cfsr & CFSR_MMARVALID != 0 && guard.contains(&((*SCB::PTR).mmfar.read() as usize))
+}
+
+#[cfg(not(feature = "stack-guard"))]
+unsafe fn hit_stack_guard(_cfsr: u32) -> bool {
+    false
+}
+
+/// Print a line for each bit of `status` found in `table`.
+fn report(output: &mut impl Write, status: u32, table: &'static [(u32, &'static str, &'static str)]) -> core::fmt::Result {
+    for (_, name, description) in reasons(status, table) {
+        writeln!(output, "  {}: {}", name, description)?;
+    }
+    Ok(())
+}
+
+/// Print the reasons in CFSR followed by the faulting address.
+fn report_cfsr(output: &mut impl Write, cfsr: u32) -> core::fmt::Result {
+    report(output, cfsr, &CFSR_REASONS)?;
+    if let Some(address) = fault_address(cfsr) {
+        writeln!(output, "  address {:#010x}", address)?;
+    }
+    Ok(())
+}
@@ -65,6 +65,14 @@ impl<T: Copy, const SIZE: usize> FIFO<T, SIZE> {
         Ok(appended)
     }
 
+    /// Data at start of buffer without removing it.
+    pub fn front(&self) -> Option<&T> {
+        if self.is_empty() {
+            return None;
+        }
+        Some(&self.data[self.read_head])
+    }
+
     /// Pop data from start of buffer.
     pub fn pop_front(&mut self) -> Option<T> {
         if self.is_empty() {
@@ -76,4 +84,18 @@ impl<T: Copy, const SIZE: usize> FIFO<T, SIZE> {
         self.count -= 1;
         Some(data)
     }
+
+    /// Pop as many elements as fit into `buffer` from start of buffer.
+    /// Returns number of elements copied.
+    pub fn pop_into(&mut self, buffer: &mut [T]) -> usize {
+        let mut popped = 0;
+        for slot in buffer.iter_mut() {
+            match self.pop_front() {
+                Some(data) => *slot = data,
+                None => break,
+            }
+            popped += 1;
+        }
+        popped
+    }
 }
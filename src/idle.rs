@@ -0,0 +1,160 @@
+//! Idle task and processor load measurement.
+//!
+//! The idle task is created together with the scheduler and only runs if no other task is ready.
+//! It calls the hooks registered with [crate::syscalls::stubs::add_idle_hook] and waits for the next
+//! interrupt with `wfi`. Cycles spent in the idle task count as unused, which gives the load.
+//! The idle task must always be ready, so if a hook makes it exit, e.g. by faulting, the hook is
+//! removed and the idle task is started over, see [restart].
+//!
+//! While only the idle task can run, SysTick is reprogrammed to fire when the next sleeping task
+//! wakes up instead of on every tick, see [prepare_ticks].
+
+use core::ops::Range;
+use crate::mpu::{Access, MpuRegion};
+use crate::task::{self, Task, TASK_TABLE};
+use crate::{log, time};
+
+/// Size of the idle task stack in words. Idle hooks run on it, too.
+const IDLE_STACK_SIZE: usize = 256;
+/// Maximum number of idle hooks.
+const MAX_IDLE_HOOKS: usize = 4;
+/// Number of windows the load is averaged over.
+const LOAD_WINDOWS: usize = 10;
+/// Length of a load window in ticks, so all windows together span one second.
+const LOAD_WINDOW_TICKS: u64 = (time::TICK_HZ as usize / LOAD_WINDOWS) as u64;
+
+/// Stack of the idle task. Aligned to its size, so the region covering it covers nothing else.
+#[repr(C, align(1024))]
+struct IdleStack([u32; IDLE_STACK_SIZE]);
+
+#[link_section = ".app_ram"]
+static mut IDLE_STACK: IdleStack = IdleStack([0; IDLE_STACK_SIZE]);
+
+/// Registered hooks and the one being called.
+/// Tasks cannot access kernel memory, so the hooks are kept in task RAM and the idle task is granted
+/// access to them. Aligned to the smallest region size, so the region covers nothing else.
+#[repr(C, align(32))]
+struct IdleHooks {
+    /// Addresses of the hooks, zero for free entries.
+    entries: [usize; MAX_IDLE_HOOKS],
+    /// One more than the index of the hook being called, zero between hooks.
+    running: usize,
+}
+
+#[link_section = ".app_ram"]
+static mut IDLE_HOOKS: IdleHooks = IdleHooks { entries: [0; MAX_IDLE_HOOKS], running: 0 };
+
+/// Idle and total cycles of one load window.
+#[derive(Clone, Copy)]
+struct LoadWindow {
+    idle: u32,
+    total: u32,
+}
+
+static mut LOAD: [LoadWindow; LOAD_WINDOWS] = [LoadWindow { idle: 0, total: 0 }; LOAD_WINDOWS];
+static mut NEXT_WINDOW: usize = 0;
+/// Cycles of the idle task and cycle counter at the start of the current window.
+static mut WINDOW_START: (u64, u32) = (0, 0);
+/// Tick the current window ends at. Windows last longer if ticks were suppressed.
+static mut WINDOW_END: u64 = LOAD_WINDOW_TICKS;
+
+/// Create the idle task. Called once when the scheduler is initialized.
+pub(crate) unsafe fn create_idle_task() {
+    // Task RAM is not initialized on reset.
+    IDLE_HOOKS = IdleHooks { entries: [0; MAX_IDLE_HOOKS], running: 0 };
+    let id = task::create_task(idle, 0, &mut IDLE_STACK.0, 0).expect("failed to create idle task");
+    let task = TASK_TABLE.find_task(id).expect("idle task not found");
+    let region = MpuRegion::covering(&hooks(), Access::ReadWrite).expect("idle hooks cannot be covered by a region");
+    assert!(task.add_region(region), "no region left for idle hooks");
+    TASK_TABLE.set_idle_task(id);
+    WINDOW_START = (0, time::cycles());
+}
+
+/// Start the idle task over after it exited, removing the hook it was calling if any.
+/// Called by the dispatcher when switching away from the exited idle task.
+pub(crate) unsafe fn restart(task: &mut Task) {
+    let running = core::mem::replace(&mut IDLE_HOOKS.running, 0);
+    if let Some(entry) = running.checked_sub(1).and_then(|index| IDLE_HOOKS.entries.get_mut(index)) {
+        log!(Warn, "Idle hook {:#010x} ended the idle task with code {}, removing it", *entry, task.exit_code().unwrap_or_default());
+        *entry = 0;
+    } else {
+        log!(Warn, "Idle task ended with code {}, restarting it", task.exit_code().unwrap_or_default());
+    }
+    task::restart_task(task, idle, 0);
+}
+
+/// Addresses of the hook table.
+fn hooks() -> Range<usize> {
+    let start = unsafe { &IDLE_HOOKS as *const IdleHooks as usize };
+    start..start + core::mem::size_of::<IdleHooks>()
+}
+
+/// Register `hook` to be called by the idle task.
+/// Returns `false` if all entries are in use.
+pub(crate) unsafe fn add_hook(hook: fn()) -> bool {
+    match IDLE_HOOKS.entries.iter_mut().find(|entry| **entry == 0) {
+        Some(entry) => {
+            *entry = hook as usize;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Entry of the idle task. Runs unprivileged like any other task.
+fn idle(_: usize) {
+    loop {
+        for index in 0..MAX_IDLE_HOOKS {
+            // Hooks may be added while we are iterating.
+            let address = unsafe { core::ptr::read_volatile(&IDLE_HOOKS.entries[index]) };
+            if address != 0 {
+                let hook = unsafe { core::mem::transmute::<usize, fn()>(address) };
+                // Tells the kernel which hook to remove if this one ends the idle task.
+                unsafe { core::ptr::write_volatile(&mut IDLE_HOOKS.running, index + 1) };
+                hook();
+                unsafe { core::ptr::write_volatile(&mut IDLE_HOOKS.running, 0) };
+            }
+        }
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Close the current load window if it is complete. Called on every tick.
+pub(crate) unsafe fn sample_load(now: u64) {
+    if now < WINDOW_END {
+        return;
+    }
+    WINDOW_END = now + LOAD_WINDOW_TICKS;
+    let idle = TASK_TABLE.idle_task().map_or(0, Task::cycles);
+    let cycles = time::cycles();
+    let (idle_start, cycles_start) = WINDOW_START;
+    LOAD[NEXT_WINDOW] = LoadWindow {
+        idle: (idle - idle_start) as u32,
+        total: cycles.wrapping_sub(cycles_start),
+    };
+    NEXT_WINDOW = (NEXT_WINDOW + 1) % LOAD_WINDOWS;
+    WINDOW_START = (idle, cycles);
+}
+
+/// Suppress ticks until the next sleeping task wakes up if the dispatcher switches to the idle
+/// task, or end the suppression if it switches to another task. Records waiting in the kernel log
+/// are printed on ticks, so ticks are only suppressed once they are out.
+pub(crate) unsafe fn prepare_ticks(next: *const Task) {
+    if !TASK_TABLE.is_idle_task(next) {
+        time::wake_from_idle();
+    } else if !log::has_undrained() {
+        let ticks = TASK_TABLE.next_wake_up().map_or(u64::MAX, |until| until.saturating_sub(time::now()));
+        time::suppress_ticks(ticks);
+    }
+}
+
+/// Percentage of cycles spent outside the idle task during the last ten windows, which span about
+/// one second.
+pub(crate) fn load() -> u32 {
+    let (idle, total) = unsafe { LOAD.iter() }
+        .fold((0u64, 0u64), |(idle, total), window| (idle + window.idle as u64, total + window.total as u64));
+    if total == 0 {
+        return 0;
+    }
+    (100 - idle.min(total) * 100 / total) as u32
+}
@@ -0,0 +1,179 @@
+//! Kernel log, a ring of text records drained to the BIOS output.
+//!
+//! Kernel code must not wait for the serial port, so messages are formatted into a record and
+//! stored in a ring, overwriting the oldest record once it is full. Every record gets a sequence
+//! number, so readers can follow the ring independently: [drain] moves whole records into the
+//! transmit buffer as space becomes available, and tasks fetch records with
+//! [crate::syscalls::stubs::read_log].
+
+use core::fmt::{Arguments, Write};
+use crate::{bios, time};
+
+/// Format a line into the kernel log at the given [Level], like [writeln!].
+macro_rules! log {
+    ($level:ident, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::$level, format_args!($($arg)*))
+    };
+}
+
+/// Maximum length of the text of a record in bytes. Longer messages are cut off.
+/// Together with its prefix, a record must fit into the transmit buffer of the BIOS.
+const RECORD_SIZE: usize = 80;
+/// Number of records kept in the ring.
+const RECORDS: usize = 32;
+/// Records less important than this are discarded.
+const DEFAULT_LEVEL: Level = Level::Info;
+
+/// Importance of a record, most important first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum Level {
+    Error = 0,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    /// Name printed in front of records, padded to the same width.
+    pub fn label(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN ",
+            Level::Info => "INFO ",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Single line of the log, filled using [Write].
+#[derive(Clone, Copy)]
+pub struct Record {
+    sequence: u32,
+    level: Level,
+    /// Tick count when the record was written.
+    timestamp: u64,
+    length: usize,
+    text: [u8; RECORD_SIZE],
+}
+
+impl Record {
+    pub const EMPTY: Self = Self { sequence: 0, level: Level::Info, timestamp: 0, length: 0, text: [0; RECORD_SIZE] };
+
+    pub fn sequence(&self) -> u32 {
+        self.sequence
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn text(&self) -> &[u8] {
+        &self.text[..self.length]
+    }
+}
+
+impl Write for Record {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        let count = string.len().min(RECORD_SIZE - self.length);
+        self.text[self.length..self.length + count].copy_from_slice(&string.as_bytes()[..count]);
+        self.length += count;
+        Ok(())
+    }
+}
+
+static mut LOG: [Record; RECORDS] = [Record::EMPTY; RECORDS];
+/// Sequence number of the next record.
+static mut NEXT_SEQUENCE: u32 = 0;
+/// Sequence number of the next record drained to the BIOS output.
+static mut CONSOLE_SEQUENCE: u32 = 0;
+static mut LEVEL: Level = DEFAULT_LEVEL;
+
+/// Discard records less important than `level` from now on.
+pub fn set_level(level: Level) {
+    unsafe { LEVEL = level };
+}
+
+/// Whether records of `level` are kept.
+pub fn enabled(level: Level) -> bool {
+    level <= unsafe { LEVEL }
+}
+
+/// Append a line to the log. Use through [log!].
+pub fn write(level: Level, message: Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let mut record = Record::EMPTY;
+    let _ = record.write_fmt(message);
+    push(level, record);
+}
+
+/// Append a formatted record to the log, overwriting the oldest one if the ring is full.
+pub fn push(level: Level, record: Record) {
+    if enabled(level) {
+        push_unfiltered(level, record);
+    }
+}
+
+/// Append `record` with `level` even if records of `level` are currently discarded, e.g. for records
+/// that were requested explicitly.
+pub fn push_unfiltered(level: Level, mut record: Record) {
+    unsafe {
+        record.sequence = NEXT_SEQUENCE;
+        record.level = level;
+        record.timestamp = time::now();
+        LOG[NEXT_SEQUENCE as usize % RECORDS] = record;
+        NEXT_SEQUENCE += 1;
+    }
+}
+
+/// Whether records are waiting to be printed by [drain].
+pub fn has_undrained() -> bool {
+    unsafe { CONSOLE_SEQUENCE < NEXT_SEQUENCE }
+}
+
+/// Sequence number the next record will get.
+pub fn next_sequence() -> u32 {
+    unsafe { NEXT_SEQUENCE }
+}
+
+/// Oldest record with a sequence number of at least `sequence`, or `None` if there is no such record
+/// yet. If records have been overwritten since, the oldest remaining record is returned.
+pub fn read(sequence: u32) -> Option<Record> {
+    unsafe {
+        let oldest = NEXT_SEQUENCE.saturating_sub(RECORDS as u32);
+        let sequence = sequence.max(oldest);
+        if sequence >= NEXT_SEQUENCE {
+            return None;
+        }
+        Some(LOG[sequence as usize % RECORDS])
+    }
+}
+
+/// Move as many complete records to the BIOS output as fit into its transmit buffer.
+/// Only called from exceptions of kernel priority.
+pub unsafe fn drain() {
+    let mut output = bios::buffered_output();
+    while let Some(record) = read(CONSOLE_SEQUENCE) {
+        let mut prefix = Record::EMPTY;
+        let lost = record.sequence - CONSOLE_SEQUENCE;
+        if lost > 0 {
+            let _ = writeln!(prefix, "{} log records lost", lost);
+        }
+        let _ = write!(prefix, "[{:>8}] {} ", record.timestamp, record.level.label());
+        if output.free_space() < prefix.length + record.length + 1 {
+            break;
+        }
+        let _ = output.append(prefix.text());
+        let _ = output.append(record.text());
+        let _ = output.append(b"\n");
+        CONSOLE_SEQUENCE = record.sequence + 1;
+    }
+}
@@ -15,26 +15,54 @@ naked_functions
 #![feature(const_trait_impl)]
 
 
-use core::{fmt::Write, panic::PanicInfo};
-use cortex_m::asm::delay;
+use core::{fmt::Write, ops::Range, panic::PanicInfo};
 use cortex_m::peripheral::scb::{SystemHandler};
+use cortex_m::register::control::Npriv;
 use cortex_m_rt::{entry, exception};
 use stm32f4xx_hal::{pac::{self}, prelude::*, serial::{Config}};
-use stm32f4xx_hal::timer::SysEvent;
 use task::{OS_CURRENT_TASK};
-use crate::task::{schedule_next_task, start_scheduler};
+use syscalls::TaskStats;
+use crate::task::{reschedule, start_scheduler};
 
+#[macro_use]
+mod log;
 mod dispatcher;
 mod task;
 mod global_peripherals;
 mod syscalls;
 mod bios;
 mod fifo;
+mod time;
+mod memory;
+mod mpu;
+mod crash;
+mod fault;
+mod console;
+mod idle;
+mod sync;
 
 
+/// Exit code of tasks that panicked.
+const PANIC_EXIT_CODE: u32 = 101;
+
 #[panic_handler]
 unsafe fn panic_handler(info: &PanicInfo) -> ! {
-    let mut output = bios::buffered_output();
+    if in_unprivileged_task() {
+        /// Tasks cannot access the BIOS, so report using system calls and only end the task.
+        let mut output = syscalls::stubs::Output;
+        report_panic(&mut output, info);
+        syscalls::stubs::exit(PANIC_EXIT_CODE)
+    }
+
+    /// Interrupts may not fire anymore, so poll the serial port.
+    let mut output = bios::raw_output();
+    report_panic(&mut output, info);
+    loop {
+        cortex_m::asm::bkpt()
+    }
+}
+
+fn report_panic(output: &mut impl Write, info: &PanicInfo) {
     if let Some(location) = info.location() {
         writeln!(
             output,
@@ -48,15 +76,23 @@ unsafe fn panic_handler(info: &PanicInfo) -> ! {
     if let Some(s) = info.payload().downcast_ref::<&str>() {
         writeln!(output, "{}\r", s).unwrap();
     }
-    loop {
-        cortex_m::asm::bkpt()
-    }
+}
+
+/// Whether we are running in thread mode without privileges, i.e. in a task.
+fn in_unprivileged_task() -> bool {
+    let ipsr: u32;
+    unsafe { core::arch::asm!("mrs {}, IPSR", out(reg) ipsr, options(nomem, nostack, preserves_flags)) };
+    ipsr == 0 && cortex_m::register::control::read().npriv() == Npriv::Unprivileged
 }
 
 #[exception]
 fn SysTick() {
-    schedule_next_task();
-    cortex_m::peripheral::SCB::set_pendsv();
+    unsafe { time::tick() };
+    task::wake_sleeping_tasks(time::now());
+    unsafe { idle::sample_load(time::now()) };
+    unsafe { console::poll() };
+    unsafe { log::drain() };
+    reschedule();
 }
 
 #[entry]
@@ -80,14 +116,17 @@ fn main() -> ! {
 
     writeln!(raw_serial, "Sysclock at {}, Hclock at {}", clocks.sysclk(), clocks.hclk()).unwrap();
     writeln!(raw_serial, "Initializing BIOS...").unwrap();
-    bios::initialize(raw_serial);
+    let mut nvic = cp.NVIC;
+    bios::initialize(raw_serial, &mut nvic);
 
+    /// Report the crash that caused the last reset before anything else can go wrong.
+    if let Some(crash) = unsafe { crash::take_previous() } {
+        let mut output = bios::raw_output();
+        write!(output, "Reset after crash!\n{}", crash).unwrap();
+    }
 
     let mut output = bios::buffered_output();
 
-    // todo!("Setup kernel space memory protection");
-    // todo!("Setup interrupt priorities");
-
     /// Set priority levels of core exceptions. Lower number => higher priority.
     let mut scb = cp.SCB;
     unsafe {
@@ -106,44 +145,129 @@ fn main() -> ! {
     }
     writeln!(output, "Exception priorities configured!").unwrap();
 
-    // write!(buffered, "Starting SysTick Timer...").unwrap();
-    // let mut systick = cp.SYST.counter_hz(&clocks);
-    // systick.listen(SysEvent::Update);
-    // systick.start(1.Hz()).unwrap();
-    // writeln!(buffered, "Done!").unwrap();
+    /// Tasks may only access flash and their own memory regions. Kernel memory is privileged-only.
+    let mut mpu = cp.MPU;
+    mpu::initialize(&mut mpu);
+    writeln!(output, "Memory protection enabled!").unwrap();
+
+    /// Faults of tasks only end the task instead of escalating to HardFault.
+    fault::initialize(&mut scb);
+    writeln!(output, "Fault handlers enabled!").unwrap();
+
+    /// Cycle counter is used to measure system calls.
+    let mut dcb = cp.DCB;
+    let mut dwt = cp.DWT;
+    time::enable_cycle_counter(&mut dcb, &mut dwt);
+
+    /// SysTick is only started together with the scheduler.
+    time::configure_tick(cp.SYST, &clocks);
+    writeln!(output, "SysTick configured at {} Hz!", time::TICK_HZ).unwrap();
 
 
     let led_pin = gpioa.pa5.into_push_pull_output();
     unsafe { global_peripherals::LED = Some(led_pin); }
 
     writeln!(output, "Starting scheduler...").unwrap();
-    unsafe { start_scheduler(&mut APPLICATION_STACK, app) }
+    let worker_memory = unsafe { WORKER_STACKS.memory() };
+    unsafe { start_scheduler(&mut APPLICATION_STACK.0, worker_memory, app) }
 }
 
 
 /// Size of application stack in words (4 bytes).
-const APP_STACK_SIZE: usize = 1280usize;
-/// Application stack used after switch to scheduler.
-static mut APPLICATION_STACK: [u32; APP_STACK_SIZE] = [0u32; APP_STACK_SIZE];
-
-fn send_blocking(message: &str) -> Result<(), syscalls::SyscallError> {
-    let buffer = message.as_bytes();
-    let mut count = 0;
-    while count < buffer.len() {
-        let to_send = &buffer[count..];
-        match syscalls::stubs::write(to_send) {
-            Ok(c) => count += c,
-            Err(syscalls::SyscallError::InsufficientSpace(c)) => count += c,
-            Err(other) => { return Err(other); }
+/// Task stacks are protected by a region covering exactly the stack, so the size in bytes must be a
+/// power of two and match the alignment of [ApplicationStack].
+const APP_STACK_SIZE: usize = 2048usize;
+
+/// Application stack used after switch to scheduler, aligned to its size.
+#[repr(C, align(8192))]
+struct ApplicationStack([u32; APP_STACK_SIZE]);
+
+#[link_section = ".app_ram"]
+static mut APPLICATION_STACK: ApplicationStack = ApplicationStack([0u32; APP_STACK_SIZE]);
+
+/// Size of worker stacks in words (4 bytes).
+const WORKER_STACK_SIZE: usize = 256usize;
+
+/// Stacks of the workers started by [app]. The application task may only spawn tasks on memory it
+/// can write to, so it is granted access to all of them. Aligned to their size, so the MPU region
+/// covers nothing else.
+#[repr(C, align(1024))]
+struct WorkerStacks {
+    echo: [u32; WORKER_STACK_SIZE],
+}
+
+impl WorkerStacks {
+    fn memory(&self) -> Range<usize> {
+        let start = self as *const Self as usize;
+        start..start + core::mem::size_of::<Self>()
+    }
+}
+
+#[link_section = ".app_ram"]
+static mut WORKER_STACKS: WorkerStacks = WorkerStacks { echo: [0u32; WORKER_STACK_SIZE] };
+
+/// Worker sending back everything received via UART, prefixed by `prompt`.
+fn echo(prompt: &'static &'static str) {
+    let mut buffer = [0u8; 16];
+    loop {
+        let count = syscalls::stubs::read(&mut buffer).expect("reading failed");
+        if count > 0 {
+            syscalls::stubs::write_blocking(prompt.as_bytes()).expect("echo failed");
+            syscalls::stubs::write_blocking(&buffer[..count]).expect("echo failed");
         }
-    };
-    Ok(())
+        syscalls::stubs::sleep(1).expect("sleeping failed");
+    }
 }
 
-fn app() -> ! {
-    let very_long_message = include_str!("main.rs");
+/// Show the processor usage of all tasks since the last refresh, like `top`.
+/// The screen is redrawn with ANSI escape sequences once per second.
+fn top() -> ! {
+    let mut output = syscalls::stubs::Output;
+    // Stats of the last refresh by task table slot.
+    let mut previous: [Option<TaskStats>; task::MAX_TASKS] = [None; task::MAX_TASKS];
     loop {
-        send_blocking(very_long_message).expect("sending failed");
-        delay(8_000_000);
+        let mut current = [None; task::MAX_TASKS];
+        for (index, stats) in current.iter_mut().enumerate() {
+            *stats = syscalls::stubs::task_stats(index as u32).expect("reading task stats failed");
+        }
+        // A slot holds a different task if the previous one exited and the slot was reused.
+        let elapsed = |slot: usize| match (previous[slot], current[slot]) {
+            (Some(before), Some(now)) if before.id == now.id => now.cycles - before.cycles,
+            (_, Some(now)) => now.cycles,
+            (_, None) => 0,
+        };
+        let total = (0..task::MAX_TASKS).map(elapsed).sum::<u64>().max(1);
+        let now = syscalls::stubs::get_time().expect("reading time failed");
+        let load = syscalls::stubs::cpu_load().expect("reading load failed");
+
+        // Clear the screen and move the cursor to the top left corner.
+        write!(output, "\x1b[2J\x1b[H").unwrap();
+        writeln!(output, "top - {} s, load {}%, {} cycles", now / time::TICK_HZ as u64, load, total).unwrap();
+        writeln!(output, "\x1b[7m  ID    CPU    SWITCHES  PREEMPTED\x1b[0m").unwrap();
+        for (slot, stats) in current.iter().enumerate() {
+            let Some(stats) = stats else {
+                continue;
+            };
+            let permille = elapsed(slot) * 1000 / total;
+            writeln!(
+                output,
+                "{:>4} {:>4}.{}% {:>11} {:>10}",
+                stats.id,
+                permille / 10,
+                permille % 10,
+                stats.switches,
+                stats.preemptions
+            ).unwrap();
+        }
+
+        previous = current;
+        syscalls::stubs::sleep(time::TICK_HZ).expect("sleeping failed");
     }
 }
+
+fn app() -> ! {
+    let stack = unsafe { &mut WORKER_STACKS.echo };
+    syscalls::stubs::spawn_with(echo, &"echo: ", stack, task::DEFAULT_PRIORITY).expect("failed to start echo worker");
+
+    top()
+}
@@ -0,0 +1,31 @@
+//! Memory layout as defined by the linker script.
+
+use core::ops::Range;
+
+extern "C" {
+    static __flash_start: u32;
+    static __flash_end: u32;
+    /// Start of memory accessible to tasks. Everything else in RAM belongs to the kernel.
+    static __app_ram_start: u32;
+    static __app_ram_end: u32;
+}
+
+/// Flash holding code and read-only data.
+pub fn flash() -> Range<usize> {
+    unsafe { (&__flash_start as *const u32 as usize)..(&__flash_end as *const u32 as usize) }
+}
+
+/// RAM for tasks. Statics are placed here with `#[link_section = ".app_ram"]`.
+pub fn app_ram() -> Range<usize> {
+    unsafe { (&__app_ram_start as *const u32 as usize)..(&__app_ram_end as *const u32 as usize) }
+}
+
+/// Whether `inner` lies completely within `outer`.
+pub fn contains(outer: &Range<usize>, inner: &Range<usize>) -> bool {
+    outer.start <= inner.start && inner.end <= outer.end
+}
+
+/// Whether `a` and `b` share at least one address.
+pub fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
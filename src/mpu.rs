@@ -0,0 +1,159 @@
+//! Memory protection using the MPU.
+//!
+//! Unprivileged tasks may only read flash and access the regions of the running task, which the
+//! dispatcher loads on every context switch. All other memory, including the kernel's RAM, is only
+//! accessible to privileged code through the default memory map.
+
+use core::ops::Range;
+use cortex_m::peripheral::MPU;
+use crate::memory;
+
+/// Number of MPU regions per task. The first one always holds the task's stack.
+pub const TASK_REGIONS: usize = 4;
+/// Region number of flash, shared by all tasks.
+const FLASH_REGION: u32 = 0;
+/// Region number of the first task region.
+const FIRST_TASK_REGION: u32 = 1;
+/// Region number of the stack guard. It follows the task regions, so it takes precedence over the
+/// region covering the stack.
+#[cfg(feature = "stack-guard")]
+const STACK_GUARD_REGION: u32 = FIRST_TASK_REGION + TASK_REGIONS as u32;
+/// Size of the stack guard in bytes, the smallest region the MPU supports.
+#[cfg(feature = "stack-guard")]
+const STACK_GUARD_SIZE: usize = 32;
+
+const CTRL_ENABLE: u32 = 1 << 0;
+/// Use the default memory map for privileged accesses not covered by a region.
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+/// Write region number together with RBAR instead of using RNR.
+const RBAR_VALID: u32 = 1 << 4;
+const RASR_ENABLE: u32 = 1 << 0;
+/// Instruction fetches from the region fault.
+const RASR_XN: u32 = 1 << 28;
+/// Cacheable bit of RASR. With TEX=000 and B=0, it selects normal, write-through memory.
+const RASR_C: u32 = 1 << 17;
+/// Shareable bit of RASR.
+const RASR_S: u32 = 1 << 18;
+/// Normal memory, not shareable, write-through (TEX=000, S=0, C=1, B=0).
+const RASR_FLASH_ATTRIBUTES: u32 = RASR_C;
+/// Normal memory, shareable, write-through (TEX=000, S=1, C=1, B=0).
+const RASR_SRAM_ATTRIBUTES: u32 = RASR_S | RASR_C;
+/// Privileged code may read and write, unprivileged code may not access the region at all.
+#[cfg(feature = "stack-guard")]
+const RASR_PRIVILEGED_ONLY: u32 = 0b001 << 24;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl Access {
+    /// AP field of RASR for unprivileged accesses. Privileged code may always read and write.
+    fn permission_bits(self) -> u32 {
+        match self {
+            Access::ReadOnly => 0b010 << 24,
+            Access::ReadWrite => 0b011 << 24,
+        }
+    }
+}
+
+/// Memory region an unprivileged task may access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MpuRegion {
+    /// Start address, aligned to `size`.
+    base: usize,
+    /// Size in bytes, a power of two and at least 32.
+    size: usize,
+    access: Access,
+}
+
+impl MpuRegion {
+    /// Region covering exactly `memory`.
+    /// Returns `None` unless `memory` is a power of two of at least 32 bytes and aligned to its size,
+    /// since a larger region would also grant access to whatever lies next to `memory`.
+    pub fn covering(memory: &Range<usize>, access: Access) -> Option<Self> {
+        let size = memory.end.checked_sub(memory.start)?;
+        if size < 32 || !size.is_power_of_two() || memory.start % size != 0 {
+            return None;
+        }
+        Some(Self { base: memory.start, size, access })
+    }
+
+    /// Addresses covered by this region.
+    pub fn memory(&self) -> Range<usize> {
+        self.base..self.base + self.size
+    }
+
+    /// Whether this region grants `access` to all of `memory`.
+    pub fn allows(&self, memory: &Range<usize>, access: Access) -> bool {
+        memory::contains(&self.memory(), memory)
+            && (self.access == Access::ReadWrite || access == Access::ReadOnly)
+    }
+
+    fn rbar(&self, number: u32) -> u32 {
+        self.base as u32 | RBAR_VALID | number
+    }
+
+    fn rasr(&self, attributes: u32) -> u32 {
+        let size_bits = (self.size.trailing_zeros() - 1) << 1;
+        self.access.permission_bits() | attributes | size_bits | RASR_ENABLE
+    }
+}
+
+/// Enable the MPU with flash as only region. Violations are handled by [crate::fault].
+pub fn initialize(mpu: &mut MPU) {
+    let flash = MpuRegion::covering(&memory::flash(), Access::ReadOnly).expect("flash cannot be covered by a region");
+    unsafe {
+        mpu.ctrl.write(0);
+        mpu.rbar.write(flash.rbar(FLASH_REGION));
+        mpu.rasr.write(flash.rasr(RASR_FLASH_ATTRIBUTES));
+        for number in FIRST_TASK_REGION..FIRST_TASK_REGION + TASK_REGIONS as u32 {
+            mpu.rnr.write(number);
+            mpu.rasr.write(0);
+        }
+        mpu.ctrl.write(CTRL_PRIVDEFENA | CTRL_ENABLE);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// Replace the task regions of the MPU. Called by the dispatcher before switching to a task.
+pub unsafe fn load_task_regions(regions: &[Option<MpuRegion>; TASK_REGIONS]) {
+    let mpu = &*MPU::PTR;
+    for (number, region) in (FIRST_TASK_REGION..).zip(regions) {
+        match region {
+            Some(region) => {
+                mpu.rbar.write(region.rbar(number));
+                mpu.rasr.write(region.rasr(RASR_SRAM_ATTRIBUTES | RASR_XN));
+            }
+            None => {
+                mpu.rnr.write(number);
+                mpu.rasr.write(0);
+            }
+        }
+    }
+}
+
+/// Make the lowest, 32 byte aligned block of `stack` inaccessible to the next task, so it faults
+/// before overflowing. The kernel can still check the canary below it.
+#[cfg(feature = "stack-guard")]
+pub unsafe fn load_stack_guard(stack: &Range<usize>) {
+    let mpu = &*MPU::PTR;
+    let size_bits = (STACK_GUARD_SIZE.trailing_zeros() - 1) << 1;
+    mpu.rbar.write(stack_guard(stack).start as u32 | RBAR_VALID | STACK_GUARD_REGION);
+    mpu.rasr.write(RASR_PRIVILEGED_ONLY | RASR_SRAM_ATTRIBUTES | RASR_XN | size_bits | RASR_ENABLE);
+}
+
+/// Addresses covered by the guard of `stack`.
+#[cfg(feature = "stack-guard")]
+pub fn stack_guard(stack: &Range<usize>) -> Range<usize> {
+    let base = (stack.start + STACK_GUARD_SIZE - 1) & !(STACK_GUARD_SIZE - 1);
+    base..base + STACK_GUARD_SIZE
+}
+
+/// Whether a task with `regions` may access all of `memory`.
+pub fn can_access(regions: &[Option<MpuRegion>; TASK_REGIONS], memory: &Range<usize>, access: Access) -> bool {
+    let in_flash = access == Access::ReadOnly && memory::contains(&memory::flash(), memory);
+    in_flash || regions.iter().flatten().any(|region| region.allows(memory, access))
+}
@@ -0,0 +1,217 @@
+//! Counting semaphores and mutexes for synchronizing tasks.
+//!
+//! Objects are kept in fixed tables in the kernel and referred to by their index as handle. Tasks
+//! waiting for an object are blocked in the task table with [WaitReason::Semaphore] or
+//! [WaitReason::Mutex]. Giving a semaphore or unlocking a mutex hands it directly to the waiting task
+//! with the highest priority, see [crate::task::TaskTable::signal], so no other task can take it
+//! before the woken task runs.
+//!
+//! Mutexes avoid priority inversion by priority inheritance: while a task holds mutexes, its
+//! priority is raised to that of the highest task waiting for any of them, also across chains of
+//! tasks waiting for each other. A mutex may also have a priority ceiling, which its holder is raised
+//! to as soon as it locks it. Whenever a task locks, unlocks or stops waiting for a mutex, the
+//! priorities of the affected holders are recomputed from the mutexes they still hold.
+
+use crate::syscalls::SyscallError;
+use crate::task::{self, WaitReason, MAX_TASKS, TASK_TABLE};
+
+/// Maximum number of semaphores.
+const MAX_SEMAPHORES: usize = 8;
+/// Maximum number of mutexes.
+const MAX_MUTEXES: usize = 8;
+
+#[derive(Clone, Copy)]
+struct Semaphore {
+    count: u32,
+    max: u32,
+}
+
+#[derive(Clone, Copy)]
+struct Mutex {
+    /// ID of the task holding the mutex.
+    owner: Option<u32>,
+    /// Priority the holder is raised to while it holds the mutex.
+    ceiling: Option<u8>,
+}
+
+static mut SEMAPHORES: [Option<Semaphore>; MAX_SEMAPHORES] = [None; MAX_SEMAPHORES];
+static mut MUTEXES: [Option<Mutex>; MAX_MUTEXES] = [None; MAX_MUTEXES];
+
+/// Store `object` in the first free entry of `table`. Returns its handle.
+fn insert<T>(table: &mut [Option<T>], object: T) -> Result<u32, SyscallError> {
+    let (handle, entry) = table.iter_mut()
+        .enumerate()
+        .find(|(_, entry)| entry.is_none())
+        .ok_or(SyscallError::ObjectTableFull)?;
+    *entry = Some(object);
+    Ok(handle as u32)
+}
+
+/// Object with `handle` in `table`.
+fn get<T>(table: &mut [Option<T>], handle: u32) -> Result<&mut T, SyscallError> {
+    table.get_mut(handle as usize)
+        .and_then(Option::as_mut)
+        .ok_or(SyscallError::NoSuchObject)
+}
+
+/// Create a semaphore holding `initial` of at most `max` units. Returns its handle.
+pub(crate) unsafe fn create_semaphore(initial: u32, max: u32) -> Result<u32, SyscallError> {
+    if max == 0 || initial > max {
+        return Err(SyscallError::InvalidArgument);
+    }
+    insert(&mut SEMAPHORES, Semaphore { count: initial, max })
+}
+
+/// Delete the semaphore with `handle`, freeing its entry for new semaphores.
+/// Fails with [SyscallError::Busy] while tasks wait for it.
+pub(crate) unsafe fn delete_semaphore(handle: u32) -> Result<(), SyscallError> {
+    get(&mut SEMAPHORES, handle)?;
+    if TASK_TABLE.highest_waiting_priority(WaitReason::Semaphore(handle)).is_some() {
+        return Err(SyscallError::Busy);
+    }
+    SEMAPHORES[handle as usize] = None;
+    Ok(())
+}
+
+/// Take a unit of the semaphore with `handle`. Returns `false` if none is left.
+pub(crate) unsafe fn take_semaphore(handle: u32) -> Result<bool, SyscallError> {
+    let semaphore = get(&mut SEMAPHORES, handle)?;
+    if semaphore.count == 0 {
+        return Ok(false);
+    }
+    semaphore.count -= 1;
+    Ok(true)
+}
+
+/// Give a unit to the semaphore with `handle`, or directly to a task waiting for one.
+pub(crate) unsafe fn give_semaphore(handle: u32) -> Result<(), SyscallError> {
+    let semaphore = get(&mut SEMAPHORES, handle)?;
+    if task::signal_task(WaitReason::Semaphore(handle)).is_some() {
+        return Ok(());
+    }
+    if semaphore.count == semaphore.max {
+        return Err(SyscallError::SemaphoreFull);
+    }
+    semaphore.count += 1;
+    Ok(())
+}
+
+/// Create an unlocked mutex with an optional priority `ceiling`. Returns its handle.
+pub(crate) unsafe fn create_mutex(ceiling: Option<u8>) -> Result<u32, SyscallError> {
+    insert(&mut MUTEXES, Mutex { owner: None, ceiling })
+}
+
+/// Delete the mutex with `handle`, freeing its entry for new mutexes.
+/// Fails with [SyscallError::Busy] while a task holds it. Tasks only wait for a locked mutex, so no
+/// task can be waiting for it either.
+pub(crate) unsafe fn delete_mutex(handle: u32) -> Result<(), SyscallError> {
+    if get(&mut MUTEXES, handle)?.owner.is_some() {
+        return Err(SyscallError::Busy);
+    }
+    MUTEXES[handle as usize] = None;
+    Ok(())
+}
+
+/// Lock the mutex with `handle` for task `id`. Returns `false` if another task holds it.
+/// Tasks whose base priority is above the ceiling of the mutex may not lock it.
+pub(crate) unsafe fn lock_mutex(handle: u32, id: u32) -> Result<bool, SyscallError> {
+    let mutex = get(&mut MUTEXES, handle)?;
+    let base_priority = TASK_TABLE.find_task(id).map_or(0, |task| task.base_priority());
+    if mutex.ceiling.is_some_and(|ceiling| base_priority > ceiling) {
+        return Err(SyscallError::AboveCeiling);
+    }
+    match mutex.owner {
+        None => {
+            mutex.owner = Some(id);
+            update_priority(id);
+            Ok(true)
+        }
+        Some(owner) if owner == id => Err(SyscallError::WouldDeadlock),
+        Some(_) => Ok(false),
+    }
+}
+
+/// Recompute the priority of the holder of the mutex with `handle` after a task started or stopped
+/// waiting for it. Returns whether the priority of any task changed.
+pub(crate) unsafe fn update_holder_priority(handle: u32) -> bool {
+    match get(&mut MUTEXES, handle).ok().and_then(|mutex| mutex.owner) {
+        Some(owner) => update_priority(owner),
+        None => false,
+    }
+}
+
+/// Unlock the mutex with `handle` held by task `id`, handing it to a waiting task if there is one.
+/// The caller falls back to the priority the mutexes it still holds justify.
+/// Returns whether the caller should be preempted, because it lost priority or the woken task has a
+/// higher priority.
+pub(crate) unsafe fn unlock_mutex(handle: u32, id: u32) -> Result<bool, SyscallError> {
+    let mutex = get(&mut MUTEXES, handle)?;
+    if mutex.owner != Some(id) {
+        return Err(SyscallError::NotOwner);
+    }
+    let woken = TASK_TABLE.signal(WaitReason::Mutex(handle)).map(|task| task.id());
+    mutex.owner = woken;
+    if let Some(woken) = woken {
+        update_priority(woken);
+    }
+    let lowered = update_priority(id);
+    let priority = |id| TASK_TABLE.find_task(id).map(|task| task.priority());
+    Ok(lowered || woken.and_then(priority) > priority(id))
+}
+
+/// Unlock all mutexes held by task `id`, which has exited.
+/// Waiting tasks get the mutexes, but are only scheduled by the next reschedule, so this may be
+/// called while switching tasks.
+pub(crate) unsafe fn release_mutexes(id: u32) {
+    for (handle, entry) in MUTEXES.iter_mut().enumerate() {
+        let Some(mutex) = entry.as_mut().filter(|mutex| mutex.owner == Some(id)) else {
+            continue;
+        };
+        mutex.owner = TASK_TABLE.signal(WaitReason::Mutex(handle as u32)).map(|task| task.id());
+        if let Some(owner) = mutex.owner {
+            update_priority(owner);
+        }
+    }
+}
+
+/// Priority task `id` with `base_priority` is entitled to by the mutexes it holds.
+unsafe fn inherited_priority(id: u32, base_priority: u8) -> u8 {
+    MUTEXES.iter()
+        .enumerate()
+        .filter_map(|(handle, entry)| Some((handle, entry.as_ref()?)))
+        .filter(|(_, mutex)| mutex.owner == Some(id))
+        .map(|(handle, mutex)| mutex.ceiling.max(TASK_TABLE.highest_waiting_priority(WaitReason::Mutex(handle as u32))))
+        .fold(base_priority, |priority, inherited| priority.max(inherited.unwrap_or(0)))
+}
+
+/// Recompute the priority of task `id` from the mutexes it holds. If it changes and the task itself
+/// waits for a mutex, the holder of that mutex is updated as well, and so on along the chain.
+/// Returns whether the priority of any task changed.
+unsafe fn update_priority(mut id: u32) -> bool {
+    let mut changed = false;
+    // A cycle in the chain is a deadlock. Limiting the steps keeps it from hanging the kernel.
+    for _ in 0..MAX_TASKS {
+        let Some(task) = TASK_TABLE.find_task(id) else {
+            break;
+        };
+        let (current, base) = (task.priority(), task.base_priority());
+        let priority = inherited_priority(id, base);
+        if priority == current {
+            break;
+        }
+        let Some(task) = TASK_TABLE.find_task(id) else {
+            break;
+        };
+        task.set_priority(priority);
+        changed = true;
+
+        let Some(WaitReason::Mutex(handle)) = task.waiting_for() else {
+            break;
+        };
+        match MUTEXES[handle as usize].and_then(|mutex| mutex.owner) {
+            Some(owner) => id = owner,
+            None => break,
+        }
+    }
+    changed
+}
@@ -0,0 +1,281 @@
+//! Conversion of system call arguments and return values into words of the argument array.
+
+use super::{FaultPolicy, Level, LogRecord, SyscallError, TaskInfo, TaskStats};
+use super::kernel_mode::{user_slice, user_slice_mut};
+use crate::memory;
+
+/// Type that can be passed to or returned from a system call.
+pub trait Argument: Sized {
+    /// Number of words in the argument array.
+    const WORDS: usize;
+
+    /// Store value in the first [Self::WORDS] words of `words`.
+    fn encode(self, words: &mut [u32]);
+
+    /// Read value from the first [Self::WORDS] words of `words`.
+    /// In the kernel, this validates the value for the calling task, e.g. that it may access a buffer.
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError>;
+}
+
+impl Argument for () {
+    const WORDS: usize = 0;
+
+    fn encode(self, _words: &mut [u32]) {}
+
+    unsafe fn decode(_words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(())
+    }
+}
+
+impl Argument for u32 {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(words[0])
+    }
+}
+
+/// Passed as low word followed by high word.
+impl Argument for u64 {
+    const WORDS: usize = 2;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+        words[1] = (self >> 32) as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok((words[1] as u64) << 32 | words[0] as u64)
+    }
+}
+
+impl Argument for usize {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(words[0] as usize)
+    }
+}
+
+impl Argument for u8 {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        u8::try_from(words[0]).map_err(|_| SyscallError::InvalidArgument)
+    }
+}
+
+impl Argument for bool {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(words[0] != 0)
+    }
+}
+
+impl Argument for FaultPolicy {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        match words[0] {
+            x if x == FaultPolicy::ReturnError as u32 => Ok(FaultPolicy::ReturnError),
+            x if x == FaultPolicy::Kill as u32 => Ok(FaultPolicy::Kill),
+            _ => Err(SyscallError::InvalidArgument),
+        }
+    }
+}
+
+impl Argument for Level {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        match words[0] {
+            x if x == Level::Error as u32 => Ok(Level::Error),
+            x if x == Level::Warn as u32 => Ok(Level::Warn),
+            x if x == Level::Info as u32 => Ok(Level::Info),
+            x if x == Level::Debug as u32 => Ok(Level::Debug),
+            x if x == Level::Trace as u32 => Ok(Level::Trace),
+            _ => Err(SyscallError::InvalidArgument),
+        }
+    }
+}
+
+impl Argument for LogRecord {
+    const WORDS: usize = 5;
+
+    fn encode(self, words: &mut [u32]) {
+        self.sequence.encode(&mut words[0..]);
+        self.level.encode(&mut words[1..]);
+        self.timestamp.encode(&mut words[2..]);
+        self.length.encode(&mut words[4..]);
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(LogRecord {
+            sequence: u32::decode(&words[0..])?,
+            level: Level::decode(&words[1..])?,
+            timestamp: u64::decode(&words[2..])?,
+            length: usize::decode(&words[4..])?,
+        })
+    }
+}
+
+impl Argument for TaskInfo {
+    const WORDS: usize = 6;
+
+    fn encode(self, words: &mut [u32]) {
+        self.id.encode(&mut words[0..]);
+        self.priority.encode(&mut words[1..]);
+        self.exit_code.encode(&mut words[2..]);
+        self.stack_size.encode(&mut words[4..]);
+        self.stack_peak.encode(&mut words[5..]);
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(TaskInfo {
+            id: u32::decode(&words[0..])?,
+            priority: u8::decode(&words[1..])?,
+            exit_code: Option::<u32>::decode(&words[2..])?,
+            stack_size: usize::decode(&words[4..])?,
+            stack_peak: usize::decode(&words[5..])?,
+        })
+    }
+}
+
+impl Argument for TaskStats {
+    const WORDS: usize = 5;
+
+    fn encode(self, words: &mut [u32]) {
+        self.id.encode(&mut words[0..]);
+        self.cycles.encode(&mut words[1..]);
+        self.switches.encode(&mut words[3..]);
+        self.preemptions.encode(&mut words[4..]);
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(TaskStats {
+            id: u32::decode(&words[0..])?,
+            cycles: u64::decode(&words[1..])?,
+            switches: u32::decode(&words[3..])?,
+            preemptions: u32::decode(&words[4..])?,
+        })
+    }
+}
+
+/// Passed as a flag word followed by the value, which is zero for `None`.
+impl<T: Argument> Argument for Option<T> {
+    const WORDS: usize = 1 + T::WORDS;
+
+    fn encode(self, words: &mut [u32]) {
+        match self {
+            Some(value) => {
+                words[0] = 1;
+                value.encode(&mut words[1..]);
+            }
+            None => words[0] = 0,
+        }
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        match words[0] {
+            0 => Ok(None),
+            _ => T::decode(&words[1..]).map(Some),
+        }
+    }
+}
+
+/// Raw pointers are passed unchecked. The handler is responsible for validating them.
+impl Argument for *mut u32 {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        Ok(words[0] as *mut u32)
+    }
+}
+
+/// Task entry points must be thumb functions in flash.
+impl Argument for fn(usize) {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as usize as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        let address = words[0] as usize;
+        if address & 1 == 0 || !memory::flash().contains(&address) {
+            return Err(SyscallError::InvalidArgument);
+        }
+        Ok(core::mem::transmute::<usize, fn(usize)>(address))
+    }
+}
+
+/// Must lie in flash like task entry points.
+impl Argument for fn() {
+    const WORDS: usize = 1;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self as usize as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        let entry = <fn(usize)>::decode(words)?;
+        Ok(core::mem::transmute::<fn(usize), fn()>(entry))
+    }
+}
+
+/// Buffers are passed as pointer and length. The calling task must be able to read them.
+impl<'a> Argument for &'a [u8] {
+    const WORDS: usize = 2;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self.as_ptr() as u32;
+        words[1] = self.len() as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        user_slice(words[0] as usize, words[1] as usize)
+    }
+}
+
+/// Buffers are passed as pointer and length. The calling task must be able to write them.
+impl<'a> Argument for &'a mut [u8] {
+    const WORDS: usize = 2;
+
+    fn encode(self, words: &mut [u32]) {
+        words[0] = self.as_mut_ptr() as u32;
+        words[1] = self.len() as u32;
+    }
+
+    unsafe fn decode(words: &[u32]) -> Result<Self, SyscallError> {
+        user_slice_mut(words[0] as usize, words[1] as usize)
+    }
+}
@@ -1,9 +1,17 @@
 //! Kernel-side code for system calls.
 //! Deals with reading call number and arguments from stack and executing the actual calls.
 
-use crate::bios;
+use core::fmt::Write;
+use crate::{bios, crash, idle, log, sync, time};
+use crate::mpu::{Access, MpuRegion};
+use crate::task::TASK_TABLE;
+use crate::task;
+use crate::task::WaitReason;
 use crate::syscalls::SyscallError;
-use super::ReturnCode;
+#[cfg(feature = "syscall-trace")]
+use super::trace;
+use super::{NO_WAIT, WAIT_FOREVER};
+use super::{Abi, Argument, FaultPolicy, Level, LogRecord, ReturnCode, SyscallNumber, TaskInfo, TaskStats, REGISTER_ARGUMENT_WORDS};
 
 #[naked]
 #[no_mangle]
@@ -52,22 +60,142 @@ pub unsafe fn SVCall() {
 }
 
 /// Decodes number and arguments for syscall from stack and calls corresponding handler.
+/// Unknown or malformed calls are answered with an error code, or end the calling task if its
+/// [FaultPolicy] says so.
 pub unsafe extern fn handle_syscall(stack_pointer: *mut u32) {
-    let number = get_syscall_number(stack_pointer).expect("invalid syscall number");
-    let args = get_syscall_arguments(stack_pointer);
-    let (result, args) = args.split_at_mut(1);
+    let number = get_syscall_number(stack_pointer);
+    #[cfg(feature = "syscall-trace")]
+    trace::begin(number);
+    let call_result = match number {
+        Some(number) if number.abi() == Abi::Registers => handle_register_call(number, stack_pointer),
+        number => handle_array_call(number, stack_pointer),
+    };
+    #[cfg(feature = "syscall-trace")]
+    trace::end(&call_result);
+    if matches!(call_result, Err(SyscallError::NotImplemented | SyscallError::InvalidArgument)) {
+        reject_bad_call();
+    }
+}
 
-    // Execute corresponding syscall handler.
-    // Data return values from handlers are returned using args.
+/// Execute a call using [Abi::Array]. Unknown calls are answered through the array as well.
+/// Returns whether the call completed.
+unsafe fn handle_array_call(number: Option<SyscallNumber>, stack_pointer: *mut u32) -> Result<bool, SyscallError> {
+    let Some(words) = get_syscall_arguments(stack_pointer) else {
+        // There is no result slot we could write to, so the code is only returned in r0.
+        *stack_pointer = ReturnCode::InvalidArgument as u32;
+        return Err(SyscallError::InvalidArgument);
+    };
+
+    let count = words.len();
+    let (code, args) = words.split_at_mut(1);
     let call_result = match number {
-        SyscallNumber::Increment => handle_syscall_increment(args),
-        SyscallNumber::Write => handle_syscall_write(args),
-        _ => Err(ReturnCode::NotImplemented)
+        Some(number) if number.word_count() == count => dispatch_syscall(number, stack_pointer, args),
+        Some(_) => Err(SyscallError::InvalidArgument),
+        None => Err(SyscallError::NotImplemented),
     };
+    match &call_result {
+        Ok(true) => code[0] = ReturnCode::Ok as u32,
+        Ok(false) => {}
+        Err(e) => {
+            let (error_code, payload) = e.encode();
+            code[0] = error_code;
+            if let Some(word) = args.first_mut() {
+                *word = payload;
+            }
+        }
+    }
+    call_result
+}
+
+/// Execute a call using [Abi::Registers] on the stacked R0-R3 of the caller.
+/// Returns whether the call completed.
+unsafe fn handle_register_call(number: SyscallNumber, stack_pointer: *mut u32) -> Result<bool, SyscallError> {
+    let registers = core::slice::from_raw_parts_mut(stack_pointer, REGISTER_ARGUMENT_WORDS);
+    let call_result = dispatch_syscall(number, stack_pointer, registers);
+    match &call_result {
+        Ok(true) => registers[2] = ReturnCode::Ok as u32,
+        Ok(false) => {}
+        Err(e) => {
+            let (error_code, payload) = e.encode();
+            registers[0] = payload;
+            registers[2] = error_code;
+        }
+    }
+    call_result
+}
+
+/// A system call being executed by the kernel.
+pub(super) struct Call<'a> {
+    stack_pointer: *mut u32,
+    /// Words the call keeps across restarts, zero on the first attempt.
+    state: &'a mut [u32],
+    restarted: bool,
+}
+
+impl Call<'_> {
+    /// Rewind return address on stack to the `svc` instruction, so the system call is executed again
+    /// when the calling task resumes. Arguments and state are left in place for the next attempt.
+    pub unsafe fn restart(&mut self) {
+        // `svc` is a 16 bit thumb instruction just before the return address.
+        *self.stack_pointer.add(6) -= 2;
+        self.restarted = true;
+    }
+}
+
+/// Bind each argument to its decoded value, starting at word `$offset`.
+macro_rules! decode_arguments {
+    ($words:ident, $offset:expr, ) => {};
+    ($words:ident, $offset:expr, $arg:ident : $ty:ty $( , $rest:ident : $rest_ty:ty )*) => {
+        let $arg = <$ty as Argument>::decode(&$words[$offset..])?;
+        decode_arguments!($words, $offset + <$ty as Argument>::WORDS, $( $rest : $rest_ty ),*);
+    };
+}
+
+/// Generates [dispatch_syscall] from the entries of [syscall_table!].
+macro_rules! define_syscall_dispatch {
+    ($(
+        $(#[$doc:meta])*
+        $variant:ident = $number:literal {
+            $vis:vis fn $stub:ident ( $( $arg:ident : $ty:ty ),* ) -> $ret:ty;
+            errors: $( $error:ident ),*;
+            state: $state:literal;
+            handler: $handler:ident;
+            abi: $abi:ident;
+        }
+    )*) => {
+        /// Decode arguments, execute corresponding syscall handler and encode its return value.
+        /// Return values are written over the arguments, unless the call restarts.
+        /// Returns whether the call completed.
+        unsafe fn dispatch_syscall(number: SyscallNumber, stack_pointer: *mut u32, words: &mut [u32]) -> Result<bool, SyscallError> {
+            match number {
+                $(
+                    SyscallNumber::$variant => {
+                        decode_arguments!(words, 0, $( $arg : $ty ),*);
+                        $(
+                            #[cfg(feature = "syscall-trace")]
+                            trace::argument(stringify!($arg), &$arg);
+                        )*
+                        let state = 0 $( + <$ty as Argument>::WORDS )*;
+                        let mut call = Call { stack_pointer, state: &mut words[state..state + $state], restarted: false };
+                        let value: $ret = $handler(&mut call, $( $arg ),*)?;
+                        if call.restarted {
+                            return Ok(false);
+                        }
+                        value.encode(words);
+                        Ok(true)
+                    }
+                )*
+            }
+        }
+    };
+}
+
+syscall_table!(define_syscall_dispatch);
 
-    match call_result {
-        Ok(_) => result[0] = 0,
-        Err(e) => result[0] = e as u32,
+/// End the calling task if it does not want to handle invalid calls itself.
+unsafe fn reject_bad_call() {
+    if TASK_TABLE.current_task().fault_policy() == FaultPolicy::Kill {
+        task::exit_current_task(task::EXIT_CODE_BAD_SYSCALL);
     }
 }
 
@@ -86,51 +214,298 @@ unsafe fn get_syscall_number(stack_pointer: *const u32) -> Option<SyscallNumber>
 }
 
 /// Extract syscall arguments from count and pointer on stack.
-unsafe fn get_syscall_arguments(stack_pointer: *const u32) -> &'static mut [u32] {
+/// Returns `None` unless the array holds at least the result slot and the caller may write to it.
+unsafe fn get_syscall_arguments(stack_pointer: *const u32) -> Option<&'static mut [u32]> {
     let count = *stack_pointer as usize;
-    let pointer = *stack_pointer.add(1) as *mut u32;
-    core::slice::from_raw_parts_mut(pointer, count)
+    let pointer = *stack_pointer.add(1) as usize;
+    if count == 0 || pointer % 4 != 0 {
+        return None;
+    }
+    let len = count.checked_mul(4)?;
+    user_slice_mut(pointer, len).ok()?;
+    Some(core::slice::from_raw_parts_mut(pointer as *mut u32, count))
 }
 
-unsafe fn handle_syscall_increment(args: &mut [u32]) -> Result<(), ReturnCode> {
-    if args[0] < 10 {
-        args[0] += 1;
+/// Check that the calling task may access `len` bytes at `ptr` with `access`.
+unsafe fn validate_user_memory(ptr: usize, len: usize, access: Access) -> Result<(), SyscallError> {
+    let end = ptr.checked_add(len).ok_or(SyscallError::InvalidArgument)?;
+    if len == 0 || TASK_TABLE.current_task().can_access(&(ptr..end), access) {
         Ok(())
     } else {
-        Err(ReturnCode::IncrementPastTen)
+        Err(SyscallError::InvalidArgument)
+    }
+}
+
+/// Buffer of `len` bytes at `ptr` passed by the calling task for reading.
+pub(super) unsafe fn user_slice(ptr: usize, len: usize) -> Result<&'static [u8], SyscallError> {
+    validate_user_memory(ptr, len, Access::ReadOnly)?;
+    if len == 0 {
+        return Ok(&[]);
+    }
+    Ok(core::slice::from_raw_parts(ptr as *const u8, len))
+}
+
+/// Buffer of `len` bytes at `ptr` passed by the calling task for writing.
+pub(super) unsafe fn user_slice_mut(ptr: usize, len: usize) -> Result<&'static mut [u8], SyscallError> {
+    validate_user_memory(ptr, len, Access::ReadWrite)?;
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    Ok(core::slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+
+unsafe fn handle_syscall_increment(_call: &mut Call, value: u32) -> Result<u32, SyscallError> {
+    if value < 10 {
+        Ok(value + 1)
+    } else {
+        Err(SyscallError::IncrementPastTen)
     }
 }
 
-unsafe fn handle_syscall_write(args: &mut [u32]) -> Result<(), ReturnCode> {
-    let len = args[0] as usize;
-    let ptr = args[1] as *const u8;
-    let buffer = core::slice::from_raw_parts(ptr, len);
+unsafe fn handle_syscall_write(_call: &mut Call, buffer: &[u8]) -> Result<usize, SyscallError> {
     let mut output = bios::buffered_output();
-    match output.append(buffer) {
-        Ok(count) => {
-            args[0] = count as u32;
-            Ok(())
-        }
+    output.append(buffer).map_err(SyscallError::InsufficientSpace)
+}
+
+/// State is the number of bytes queued by earlier attempts.
+/// If the transmit buffer fills up, the caller is blocked until it drains and the call restarts
+/// with the remaining bytes.
+unsafe fn handle_syscall_write_blocking(call: &mut Call, buffer: &[u8]) -> Result<usize, SyscallError> {
+    let written = call.state[0] as usize;
+    let remaining = buffer.get(written..).ok_or(SyscallError::InvalidArgument)?;
+    let mut output = bios::buffered_output();
+    match output.append(remaining) {
+        Ok(count) => Ok(written + count),
         Err(appended) => {
-            args[0] = appended as u32;
-            Err(ReturnCode::InsufficientSpace)
+            call.state[0] = (written + appended) as u32;
+            call.restart();
+            task::block_current_task(WaitReason::TxSpace, None);
+            Ok(0)
         }
     }
 }
 
-/// Internal representation of system calls.
-#[derive(Debug)]
-pub(super) enum SyscallNumber {
-    Increment,
-    Write,
+unsafe fn handle_syscall_read(_call: &mut Call, buffer: &mut [u8]) -> Result<usize, SyscallError> {
+    let mut input = bios::buffered_input();
+    Ok(input.read(buffer))
+}
+
+unsafe fn handle_syscall_exit(_call: &mut Call, code: u32) -> Result<(), SyscallError> {
+    task::exit_current_task(code);
+    Ok(())
+}
+
+/// State is the 64 bit deadline, which is zero on the first attempt. The call restarts until the
+/// deadline has passed, in case the caller is resumed early because no other task could run.
+unsafe fn handle_syscall_sleep(call: &mut Call, ticks: u32) -> Result<(), SyscallError> {
+    let mut deadline = (call.state[1] as u64) << 32 | call.state[0] as u64;
+    if deadline == 0 {
+        deadline = time::now() + ticks as u64;
+    }
+    if time::now() < deadline {
+        call.state[0] = deadline as u32;
+        call.state[1] = (deadline >> 32) as u32;
+        call.restart();
+        task::sleep_current_task(deadline);
+    }
+    Ok(())
+}
+
+unsafe fn handle_syscall_yield(_call: &mut Call) -> Result<(), SyscallError> {
+    task::yield_current_task();
+    Ok(())
+}
+
+unsafe fn handle_syscall_get_time(_call: &mut Call) -> Result<u64, SyscallError> {
+    Ok(time::now())
+}
+
+/// `stack_len` is in words. Returns the ID of the new task.
+/// Tasks may not start tasks of a higher priority than their own base priority, otherwise any task
+/// could take over the processor. A priority inherited through a mutex does not count.
+unsafe fn handle_syscall_spawn(_call: &mut Call, entry: fn(usize), param: usize, stack: *mut u32, stack_len: usize, priority: u8) -> Result<u32, SyscallError> {
+    let caller = TASK_TABLE.current_task();
+    if priority > caller.base_priority() {
+        return Err(SyscallError::InvalidArgument);
+    }
+    // Workers inherit the fault policy of their creator.
+    let policy = caller.fault_policy();
+    if !task::validate_stack(stack as usize, stack_len) {
+        return Err(SyscallError::InvalidStack);
+    }
+    let stack = core::slice::from_raw_parts_mut(stack, stack_len);
+    let id = task::create_task(entry, param, stack, priority).ok_or(SyscallError::TaskTableFull)?;
+    TASK_TABLE.find_task(id).ok_or(SyscallError::NoSuchTask)?.set_fault_policy(policy);
+    Ok(id)
+}
+
+/// The caller may only share memory it can access itself, with at most the same access. Only memory
+/// the MPU can protect exactly may be shared.
+unsafe fn handle_syscall_grant(_call: &mut Call, id: u32, memory: &[u8], writable: bool) -> Result<(), SyscallError> {
+    let start = memory.as_ptr() as usize;
+    let end = start.checked_add(memory.len()).ok_or(SyscallError::AccessDenied)?;
+    let access = if writable { Access::ReadWrite } else { Access::ReadOnly };
+
+    let region = MpuRegion::covering(&(start..end), access).ok_or(SyscallError::InvalidArgument)?;
+    if !TASK_TABLE.current_task().can_access(&region.memory(), access) {
+        return Err(SyscallError::AccessDenied);
+    }
+    let task = TASK_TABLE.find_task(id).ok_or(SyscallError::NoSuchTask)?;
+    if task.add_region(region) {
+        Ok(())
+    } else {
+        Err(SyscallError::TooManyRegions)
+    }
+}
+
+unsafe fn handle_syscall_set_fault_policy(_call: &mut Call, policy: FaultPolicy) -> Result<(), SyscallError> {
+    TASK_TABLE.current_task().set_fault_policy(policy);
+    Ok(())
+}
+
+unsafe fn handle_syscall_set_trace(_call: &mut Call, enabled: bool, task: u32, syscalls: u32) -> Result<(), SyscallError> {
+    #[cfg(feature = "syscall-trace")]
+    {
+        trace::configure(enabled, task, syscalls);
+        Ok(())
+    }
+    #[cfg(not(feature = "syscall-trace"))]
+    {
+        // Not a bad call, so the caller is not ended for it whatever its fault policy.
+        let _ = (enabled, task, syscalls);
+        Err(SyscallError::FeatureDisabled)
+    }
+}
+
+unsafe fn handle_syscall_read_log(_call: &mut Call, sequence: u32, buffer: &mut [u8]) -> Result<Option<LogRecord>, SyscallError> {
+    let Some(record) = log::read(sequence) else {
+        return Ok(None);
+    };
+    let length = record.text().len().min(buffer.len());
+    buffer[..length].copy_from_slice(&record.text()[..length]);
+    Ok(Some(LogRecord {
+        sequence: record.sequence(),
+        level: record.level(),
+        timestamp: record.timestamp(),
+        length,
+    }))
+}
+
+unsafe fn handle_syscall_set_log_level(_call: &mut Call, level: Level) -> Result<(), SyscallError> {
+    log::set_level(level);
+    Ok(())
+}
+
+unsafe fn handle_syscall_read_crash_report(_call: &mut Call, buffer: &mut [u8]) -> Result<Option<usize>, SyscallError> {
+    let Some(record) = crash::previous() else {
+        return Ok(None);
+    };
+    let mut writer = crash::BufferWriter::new(buffer);
+    let _ = write!(writer, "{}", record);
+    Ok(Some(writer.len()))
+}
+
+unsafe fn handle_syscall_task_info(_call: &mut Call, index: u32) -> Result<Option<TaskInfo>, SyscallError> {
+    Ok(TASK_TABLE.tasks().nth(index as usize).map(|task| TaskInfo {
+        id: task.id(),
+        priority: task.priority(),
+        exit_code: task.exit_code(),
+        stack_size: task.stack().len(),
+        stack_peak: task.stack_peak(),
+    }))
+}
+
+unsafe fn handle_syscall_task_stats(_call: &mut Call, index: u32) -> Result<Option<TaskStats>, SyscallError> {
+    Ok(TASK_TABLE.tasks().nth(index as usize).map(|task| TaskStats {
+        id: task.id(),
+        cycles: task.cycles(),
+        switches: task.switches(),
+        preemptions: task.preemptions(),
+    }))
+}
+
+unsafe fn handle_syscall_add_idle_hook(_call: &mut Call, hook: fn()) -> Result<(), SyscallError> {
+    if !idle::add_hook(hook) {
+        return Err(SyscallError::TooManyIdleHooks);
+    }
+    Ok(())
+}
+
+unsafe fn handle_syscall_cpu_load(_call: &mut Call) -> Result<u32, SyscallError> {
+    Ok(idle::load())
 }
 
-impl SyscallNumber {
-    pub fn from(imm: u8) -> Option<Self> {
-        match imm {
-            x if x == Self::Increment as u8 => Some(Self::Increment),
-            x if x == Self::Write as u8 => Some(Self::Write),
-            other => None,
+/// Block the caller until it is handed the object `reason` stands for, and restart the call.
+/// State is the 64 bit deadline like for [handle_syscall_sleep], computed from `timeout` on the
+/// first attempt. Fails once the deadline has passed.
+unsafe fn wait_for(call: &mut Call, reason: WaitReason, timeout: u32) -> Result<(), SyscallError> {
+    let deadline = match timeout {
+        NO_WAIT => return Err(SyscallError::TimedOut),
+        WAIT_FOREVER => None,
+        _ => {
+            let mut deadline = (call.state[1] as u64) << 32 | call.state[0] as u64;
+            if deadline == 0 {
+                deadline = time::now() + timeout as u64;
+                call.state[0] = deadline as u32;
+                call.state[1] = (deadline >> 32) as u32;
+            }
+            if time::now() >= deadline {
+                return Err(SyscallError::TimedOut);
+            }
+            Some(deadline)
         }
+    };
+    call.restart();
+    task::block_current_task(reason, deadline);
+    Ok(())
+}
+
+unsafe fn handle_syscall_semaphore_create(_call: &mut Call, initial: u32, max: u32) -> Result<u32, SyscallError> {
+    sync::create_semaphore(initial, max)
+}
+
+/// A restarted call succeeds if the caller was handed a unit while it was blocked.
+unsafe fn handle_syscall_semaphore_take(call: &mut Call, handle: u32, timeout: u32) -> Result<(), SyscallError> {
+    if task::take_signal() || sync::take_semaphore(handle)? {
+        return Ok(());
     }
-}
\ No newline at end of file
+    wait_for(call, WaitReason::Semaphore(handle), timeout)
+}
+
+unsafe fn handle_syscall_semaphore_give(_call: &mut Call, handle: u32) -> Result<(), SyscallError> {
+    sync::give_semaphore(handle)
+}
+
+unsafe fn handle_syscall_semaphore_delete(_call: &mut Call, handle: u32) -> Result<(), SyscallError> {
+    sync::delete_semaphore(handle)
+}
+
+unsafe fn handle_syscall_mutex_create(_call: &mut Call, ceiling: Option<u8>) -> Result<u32, SyscallError> {
+    sync::create_mutex(ceiling)
+}
+
+/// A restarted call succeeds if the caller was handed the mutex while it was blocked.
+/// While the caller waits, the holder inherits its priority, and loses it again on timeout.
+unsafe fn handle_syscall_mutex_lock(call: &mut Call, handle: u32, timeout: u32) -> Result<(), SyscallError> {
+    let id = TASK_TABLE.current_task().id();
+    if task::take_signal() || sync::lock_mutex(handle, id)? {
+        // A ceiling only ever raises the priority of the caller, so it keeps running.
+        return Ok(());
+    }
+    let result = wait_for(call, WaitReason::Mutex(handle), timeout);
+    if sync::update_holder_priority(handle) {
+        task::reschedule();
+    }
+    result
+}
+
+unsafe fn handle_syscall_mutex_unlock(_call: &mut Call, handle: u32) -> Result<(), SyscallError> {
+    if sync::unlock_mutex(handle, TASK_TABLE.current_task().id())? {
+        task::reschedule();
+    }
+    Ok(())
+}
+
+unsafe fn handle_syscall_mutex_delete(_call: &mut Call, handle: u32) -> Result<(), SyscallError> {
+    sync::delete_mutex(handle)
+}
@@ -1,54 +1,291 @@
 //! System Calls are implemented here.
 //!
 //! ## How it works:
+//! All system calls are declared once in [table], which generates the numbering in [SyscallNumber],
+//! the user-mode functions in [stubs] and the decoding in [kernel_mode]. Errors are declared once
+//! in [syscall_errors!] below.
+//!
 //! Users call the respective safe functions with fitting arguments.
-//! Internally, parameters are converted to u32 and stored in an array of fitting size.
-//! The length and pointer to this array are stored in R0 and R1 and an `svc <number>` instruction
-//! is executed where `<number>` is a constant unique to this system call.
-//! R2 contains a pointer to a result u32 indicating success.
+//! Internally, parameters are converted to u32 (see [arguments::Argument]) and passed using the
+//! [Abi] of the call, after which an `svc <number>` instruction is executed where `<number>` is a
+//! constant unique to this system call:
+//! - [Abi::Array] stores them in an array laid out as `[code, arguments..., state...]`, where the
+//!   state words are kept by the kernel across restarts of a blocking call.
+//!   The length and pointer to this array are stored in R0 and R1.
+//!   The kernel writes a [ReturnCode] to `code` and the return value, or the payload of an error,
+//!   to the words following it.
+//! - [Abi::Registers] passes arguments and state in R0-R3. The kernel writes the return value, or
+//!   the payload of an error, to R0-R1 and the [ReturnCode] to R2 in the stacked registers of the
+//!   caller. This avoids the memory indirection for short, frequent calls.
 //!
 //! The `svc` instruction triggers the [kernel_mode::SVCall] exception handler, which looks at the stack to determine
 //! length and pointer to the arguments and calls [kernel_mode::handle_syscall] with the call number.
 //! This function decodes the number into the appropriate action and passes along the parameters.
 //! The challenge here lies in determining proper calling convention for [kernel_mode::SVCall] to [kernel_mode::handle_syscall]
 //! for arguments and return values.
+//!
+//! The kernel never trusts pointers passed by tasks. The argument array and every buffer are
+//! checked against the memory regions of the calling task before they are accessed, failing with
+//! [ReturnCode::InvalidArgument]. If the argument array itself is invalid, this code is written to
+//! R0 instead, since there is no result slot to write to. Calls using [Abi::Registers] only read
+//! the caller's exception frame, so nothing needs to be validated besides buffer arguments.
 
+#[macro_use]
+mod table;
+mod arguments;
 mod kernel_mode;
 pub mod stubs;
+#[cfg(feature = "syscall-trace")]
+mod trace;
 
-/// Returned from system call.
-/// Users should not use this directly but instead handle [Result<_, SyscallError>] where possible.
-#[derive(Debug)]
-#[repr(u32)]
-pub enum ReturnCode {
-    /// Operation succeeded.
-    Ok = 0,
-    /// Call has not yet been implemented.
-    NotImplemented,
-    /// Number ten was passed to Increment.
-    IncrementPastTen,
-    /// Insufficient space while writing.
-    InsufficientSpace,
+use arguments::Argument;
+
+/// Generates [ReturnCode], [SyscallError] and the conversion between both.
+/// Errors with a payload carry one value, which is passed in the word after the code.
+macro_rules! syscall_errors {
+    ($( $(#[$doc:meta])* $name:ident $(($field:ident: $payload:ty))? = $code:literal, )*) => {
+        /// Returned from system call.
+        /// Users should not use this directly but instead handle [Result<_, SyscallError>] where possible.
+        #[derive(Debug)]
+        #[repr(u32)]
+        pub enum ReturnCode {
+            /// Operation succeeded.
+            Ok = 0,
+            $( $(#[$doc])* $name = $code, )*
+        }
+
+        #[derive(Debug)]
+        pub enum SyscallError {
+            /// An unknown error code was encountered. Contains the invalid return code.
+            Unknown(u32),
+            $( $(#[$doc])* $name $(($payload))?, )*
+        }
+
+        impl SyscallError {
+            /// Return code and payload word passed to the caller.
+            fn encode(&self) -> (u32, u32) {
+                match self {
+                    Self::Unknown(code) => (*code, 0),
+                    $( Self::$name $(($field))? => (ReturnCode::$name as u32, error_payload!($(*$field as u32)?)), )*
+                }
+            }
+        }
+
+        /// Helper function to decode errors from return code and payload word.
+        fn decode_error(code: u32, payload: u32) -> SyscallError {
+            match code {
+                $( $code => SyscallError::$name $((payload as $payload))?, )*
+                other => SyscallError::Unknown(other),
+            }
+        }
+    };
 }
 
-#[derive(Debug)]
-pub enum SyscallError {
-    /// An unknown error code was encountered. Contains the invalid return code.
-    Unknown(u32),
+/// Payload word of an error, zero if it has none.
+macro_rules! error_payload {
+    () => { 0 };
+    ($payload:expr) => { $payload };
+}
+
+syscall_errors! {
     /// Call has not yet been implemented.
-    NotImplemented,
+    NotImplemented = 1,
     /// Number ten was passed to Increment.
-    IncrementPastTen,
+    IncrementPastTen = 2,
     /// Insufficient space while writing. Contains number of elements successfully written.
-    InsufficientSpace(usize),
+    InsufficientSpace(written: usize) = 3,
+    /// Stack passed to Spawn is too small, not a power of two aligned to its size, outside of task RAM,
+    /// not writable by the caller or already in use.
+    InvalidStack = 4,
+    /// No free slot for another task.
+    TaskTableFull = 5,
+    /// Caller may not access the given memory.
+    AccessDenied = 6,
+    /// No running task with the given ID.
+    NoSuchTask = 7,
+    /// Task has no free memory region left.
+    TooManyRegions = 8,
+    /// A pointer argument refers to memory the caller may not access, or a length is out of range.
+    InvalidArgument = 9,
+    /// All idle hooks are in use.
+    TooManyIdleHooks = 10,
+    /// No free slot for another semaphore or mutex.
+    ObjectTableFull = 11,
+    /// No semaphore or mutex with the given handle.
+    NoSuchObject = 12,
+    /// Timeout passed before the semaphore or mutex could be taken.
+    TimedOut = 13,
+    /// Caller does not hold the mutex.
+    NotOwner = 14,
+    /// Caller already holds the mutex.
+    WouldDeadlock = 15,
+    /// Semaphore already holds its maximum number of units.
+    SemaphoreFull = 16,
+    /// Caller's priority is above the ceiling of the mutex.
+    AboveCeiling = 17,
+    /// Call is known, but the kernel was built without the feature it needs.
+    FeatureDisabled = 18,
+    /// Tasks still wait for the semaphore, or a task holds the mutex.
+    Busy = 19,
+}
+
+/// How the kernel treats unknown or malformed system calls of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum FaultPolicy {
+    /// Return [ReturnCode::NotImplemented] or [ReturnCode::InvalidArgument] to the caller.
+    ReturnError = 0,
+    /// End the calling task.
+    Kill,
+}
+
+/// How arguments and return values of a system call are passed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Abi {
+    /// In an array in the caller's memory, passed by length and pointer.
+    Array,
+    /// In the stacked registers of the caller.
+    Registers,
+}
+
+pub use crate::log::Level;
+
+/// Kernel log record returned by [stubs::read_log].
+#[derive(Debug, Clone, Copy)]
+pub struct LogRecord {
+    /// Position of the record in the log. Read the next record with `sequence + 1`.
+    pub sequence: u32,
+    pub level: Level,
+    /// Tick count when the record was written.
+    pub timestamp: u64,
+    /// Number of bytes of text copied into the buffer.
+    pub length: usize,
 }
 
-/// Helper function to decode errors from an argument array.
-fn decode_error(code: u32, args: &[u32]) -> SyscallError {
-    match code {
-        x if x == ReturnCode::NotImplemented as u32 => SyscallError::NotImplemented,
-        x if x == ReturnCode::IncrementPastTen as u32 => SyscallError::IncrementPastTen,
-        x if x == ReturnCode::InsufficientSpace as u32 => SyscallError::InsufficientSpace(args[0] as usize),
-        other => SyscallError::Unknown(other),
-    }
+/// Task description returned by [stubs::task_info].
+#[derive(Debug, Clone, Copy)]
+pub struct TaskInfo {
+    pub id: u32,
+    pub priority: u8,
+    /// Exit code if the task has finished. Its slot may be reused by a new task.
+    pub exit_code: Option<u32>,
+    /// Size of the task's stack in bytes.
+    pub stack_size: usize,
+    /// Most bytes of its stack the task has used so far.
+    pub stack_peak: usize,
+}
+
+/// Processor usage of a task returned by [stubs::task_stats].
+#[derive(Debug, Clone, Copy)]
+pub struct TaskStats {
+    pub id: u32,
+    /// Cycles the task has been running for, including time spent in the kernel on its behalf.
+    pub cycles: u64,
+    /// Number of times the task was switched to.
+    pub switches: u32,
+    /// Number of times the task was switched away from although it could have continued.
+    pub preemptions: u32,
+}
+
+/// Timeout for taking a semaphore or mutex that fails immediately if it is not available.
+pub const NO_WAIT: u32 = 0;
+/// Timeout for taking a semaphore or mutex that waits until it is available.
+pub const WAIT_FOREVER: u32 = u32::MAX;
+
+/// Trace filter matching calls of every task, see [stubs::set_trace].
+pub const TRACE_ALL_TASKS: u32 = u32::MAX;
+/// Trace filter matching every call, see [stubs::set_trace].
+pub const TRACE_ALL_SYSCALLS: u32 = u32::MAX;
+
+/// Number of words passed in registers R0-R3 with [Abi::Registers].
+const REGISTER_ARGUMENT_WORDS: usize = 4;
+/// Number of words returned in registers R0-R1 with [Abi::Registers].
+const REGISTER_RETURN_WORDS: usize = 2;
+
+/// Generates [SyscallNumber] from the entries of [syscall_table!].
+macro_rules! define_syscall_numbers {
+    ($(
+        $(#[$doc:meta])*
+        $variant:ident = $number:literal {
+            $vis:vis fn $stub:ident ( $( $arg:ident : $ty:ty ),* ) -> $ret:ty;
+            errors: $( $error:ident ),*;
+            state: $state:literal;
+            handler: $handler:ident;
+            abi: $abi:ident;
+        }
+    )*) => {
+        /// Internal representation of system calls.
+        /// The discriminant is the immediate of the `svc` instruction.
+        #[derive(Debug, Clone, Copy)]
+        #[repr(u8)]
+        pub enum SyscallNumber {
+            $(
+                #[doc = concat!("See [stubs::", stringify!($stub), "].")]
+                $variant = $number,
+            )*
+        }
+
+        impl SyscallNumber {
+            fn from(imm: u8) -> Option<Self> {
+                match imm {
+                    $( $number => Some(Self::$variant), )*
+                    _ => None,
+                }
+            }
+
+            /// Bit of this call in the mask passed to [stubs::set_trace].
+            pub const fn mask(self) -> u32 {
+                1 << self as u32
+            }
+
+            const fn abi(self) -> Abi {
+                match self {
+                    $( Self::$variant => Abi::$abi, )*
+                }
+            }
+
+            /// Number of words holding arguments and state.
+            const fn input_words(self) -> usize {
+                match self {
+                    $( Self::$variant => 0 $( + <$ty as Argument>::WORDS )* + $state, )*
+                }
+            }
+
+            /// Number of words holding the return value.
+            const fn output_words(self) -> usize {
+                match self {
+                    $( Self::$variant => <$ret as Argument>::WORDS, )*
+                }
+            }
+
+            /// Length of the argument array including the return code.
+            /// It holds either arguments and state or the return value, and at least an error payload.
+            const fn word_count(self) -> usize {
+                1 + max(max(self.input_words(), self.output_words()), 1)
+            }
+
+            /// Whether arguments, state and return value fit into the registers of [Abi::Registers].
+            const fn fits_abi(self) -> bool {
+                match self.abi() {
+                    Abi::Array => true,
+                    Abi::Registers => {
+                        self.input_words() <= REGISTER_ARGUMENT_WORDS && self.output_words() <= REGISTER_RETURN_WORDS
+                    }
+                }
+            }
+        }
+
+        $(
+            const _: () = assert!(
+                SyscallNumber::$variant.fits_abi(),
+                concat!("Arguments of ", stringify!($variant), " do not fit into registers"),
+            );
+        )*
+    };
+}
+
+syscall_table!(define_syscall_numbers);
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b { a } else { b }
 }
@@ -3,58 +3,265 @@
 //! Also returning errors in a nice format.
 //! Any validation here needs to be repeated in kernel for security.
 
-use super::SyscallError;
-use super::ReturnCode;
-use super::kernel_mode::SyscallNumber;
+use core::fmt::Write;
+use crate::memory;
+use super::{Argument, FaultPolicy, Level, LogRecord, SyscallError, SyscallNumber, TaskInfo, TaskStats};
+use super::{decode_error, ReturnCode, REGISTER_ARGUMENT_WORDS, NO_WAIT};
 
-macro_rules! build_args {
-    ($n:pat $( , $arg:expr )*) => {
-        [0u32 $( , $arg )*]
+/// Store each argument in the words starting at `$offset`.
+macro_rules! encode_arguments {
+    ($words:ident, $offset:expr, ) => {};
+    ($words:ident, $offset:expr, $arg:ident : $ty:ty $( , $rest:ident : $rest_ty:ty )*) => {
+        <$ty as Argument>::encode($arg, &mut $words[$offset..]);
+        encode_arguments!($words, $offset + <$ty as Argument>::WORDS, $( $rest : $rest_ty ),*);
     };
 }
 
-macro_rules! exec_syscall {
-    ($number:expr , $count:expr $( , $arg:expr )*) => {
-        {
-            let mut args : [u32; $count + 1] = [0u32 $( , $arg as u32 )*];
-            let count = args.len() as u32;
-            let pointer = args.as_mut_ptr() as u32;
-            unsafe {
-                core::arch::asm!(
-                // Setup count and pointer to argument array.
-                "mov r0, {count}",
-                "mov r1, {pointer}",
-                // Execute system call.
-                "svc {number}",
-                count = in(reg) count,
-                pointer = in(reg) pointer,
-                number = const $number as u32,
-                // Clobber count and pointer registers.
-                out("r0") _,
-                out("r1") _,
-                );
-            }
-            let code = args[0];
-            let mut ret_args: [u32; $count] = [0u32;$count];
-            ret_args.copy_from_slice(&args[1..]);
-            if code == ReturnCode::Ok as u32 {
-                Ok(ret_args)
-            } else {
-                Err(super::decode_error(code, &ret_args))
-            }
+/// Generates a function for each entry of [syscall_table!], which encodes the arguments, executes
+/// the system call and decodes its return value or error.
+macro_rules! define_syscall_stubs {
+    ($(
+        $(#[$doc:meta])*
+        $variant:ident = $number:literal {
+            $vis:vis fn $stub:ident ( $( $arg:ident : $ty:ty ),* ) -> $ret:ty;
+            errors: $( $error:ident ),*;
+            state: $state:literal;
+            handler: $handler:ident;
+            abi: $abi:ident;
         }
+    )*) => {
+        $(
+            $(#[$doc])*
+            #[doc = ""]
+            #[doc = concat!("Errors: [SyscallError::NotImplemented], [SyscallError::InvalidArgument]" $( , ", [SyscallError::", stringify!($error), "]" )*)]
+            $vis fn $stub($( $arg: $ty ),*) -> Result<$ret, SyscallError> {
+                syscall_stub_body!($abi, $variant, $ret, $( $arg : $ty ),*)
+            }
+        )*
     };
 }
 
-/// Increment `value` by one and return it.
-pub fn increment(value: u32) -> Result<u32, SyscallError> {
-    exec_syscall!(SyscallNumber::Increment, 1, value).map(|args| args[0])
+/// Body of a stub for the [super::Abi] of the call.
+macro_rules! syscall_stub_body {
+    (Array, $variant:ident, $ret:ty, $( $arg:ident : $ty:ty ),*) => {{
+        let mut words = [0u32; SyscallNumber::$variant.word_count()];
+        encode_arguments!(words, 1, $( $arg : $ty ),*);
+        let count = words.len() as u32;
+        let pointer = words.as_mut_ptr() as u32;
+        unsafe {
+            core::arch::asm!(
+            // Setup count and pointer to argument array.
+            "mov r0, {count}",
+            "mov r1, {pointer}",
+            // Execute system call.
+            "svc {number}",
+            count = in(reg) count,
+            pointer = in(reg) pointer,
+            number = const SyscallNumber::$variant as u8,
+            // Clobber count and pointer registers.
+            out("r0") _,
+            out("r1") _,
+            );
+        }
+        if words[0] == ReturnCode::Ok as u32 {
+            unsafe { <$ret as Argument>::decode(&words[1..]) }
+        } else {
+            Err(decode_error(words[0], words[1]))
+        }
+    }};
+    (Registers, $variant:ident, $ret:ty, $( $arg:ident : $ty:ty ),*) => {{
+        // Calls without arguments leave the words untouched.
+        #[allow(unused_mut)]
+        let mut words = [0u32; REGISTER_ARGUMENT_WORDS];
+        encode_arguments!(words, 0, $( $arg : $ty ),*);
+        let [mut r0, mut r1, mut r2, r3] = words;
+        unsafe {
+            core::arch::asm!(
+            // Arguments are already in R0-R3. Results are returned in R0-R2, R3 may hold state.
+            "svc {number}",
+            number = const SyscallNumber::$variant as u8,
+            inout("r0") r0,
+            inout("r1") r1,
+            inout("r2") r2,
+            inout("r3") r3 => _,
+            );
+        }
+        if r2 == ReturnCode::Ok as u32 {
+            unsafe { <$ret as Argument>::decode(&[r0, r1]) }
+        } else {
+            Err(decode_error(r2, r0))
+        }
+    }};
+}
+
+syscall_table!(define_syscall_stubs);
+
+/// Start a new task executing `entry(param)` on `stack` with the given `priority`, which may not be
+/// higher than the priority the caller was created with.
+/// The stack is owned by the new task from now on.
+/// Returns the ID of the new task.
+pub fn spawn(entry: fn(usize), param: usize, stack: &'static mut [u32], priority: u8) -> Result<u32, SyscallError> {
+    spawn_task(entry, param, stack.as_mut_ptr(), stack.len(), priority)
+}
+
+/// Start a new task executing `entry(config)`, see [spawn].
+/// Allows starting the same function several times with different configurations.
+/// The new task may only access its own stack, so `config` and everything it refers to must lie in
+/// flash, e.g. in a constant or string literal. Fails with [SyscallError::AccessDenied] otherwise.
+pub fn spawn_with<T: Sync>(entry: fn(&'static T), config: &'static T, stack: &'static mut [u32], priority: u8) -> Result<u32, SyscallError> {
+    let start = config as *const T as usize;
+    let size = core::mem::size_of::<T>();
+    if size > 0 && !memory::contains(&memory::flash(), &(start..start + size)) {
+        return Err(SyscallError::AccessDenied);
+    }
+    // References are passed in R0 just like `usize`, so both function types share the same ABI.
+    let entry = unsafe { core::mem::transmute::<fn(&'static T), fn(usize)>(entry) };
+    spawn(entry, start, stack, priority)
 }
 
-/// Read from USART2 into `buffer`.
-/// Returns number of bytes read (at most `buffer.len()`) or error.
-pub fn write(buffer: &[u8]) -> Result<usize, SyscallError> {
-    exec_syscall!(SyscallNumber::Write, 2, buffer.len(), buffer.as_ptr())
-        // read returns number of bytes in first argument.
-        .map(|args| args[0] as usize)
-}
\ No newline at end of file
+/// Terminate the calling task with exit `code`.
+/// The kernel frees the task's slot and never schedules it again.
+pub fn exit(code: u32) -> ! {
+    let _ = exit_task(code);
+    // Only reached if no other task is ready to take over.
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Formatted output for tasks using [write_blocking].
+pub struct Output;
+
+impl Write for Output {
+    fn write_str(&mut self, string: &str) -> core::fmt::Result {
+        write_blocking(string.as_bytes()).map(|_| ()).map_err(|_| core::fmt::Error)
+    }
+}
+
+/// Counting semaphore shared between tasks.
+/// The semaphore is deleted when the value returned by [Semaphore::new] is dropped, so the creating
+/// task must keep it until the tasks it passed the handle to are done with it.
+#[derive(Debug)]
+pub struct Semaphore {
+    handle: u32,
+    /// Whether this task created the semaphore and deletes it when dropped.
+    owned: bool,
+}
+
+impl Semaphore {
+    /// Create a semaphore holding `initial` of at most `max` units.
+    pub fn new(initial: u32, max: u32) -> Result<Self, SyscallError> {
+        semaphore_create(initial, max).map(|handle| Self { handle, owned: true })
+    }
+
+    /// Semaphore with a handle received from another task. It is not deleted when dropped.
+    pub fn from_handle(handle: u32) -> Self {
+        Self { handle, owned: false }
+    }
+
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    /// Take a unit, waiting up to `timeout` ticks for one, see [semaphore_take].
+    pub fn take(&self, timeout: u32) -> Result<(), SyscallError> {
+        semaphore_take(self.handle, timeout)
+    }
+
+    /// Take a unit if one is available without waiting.
+    pub fn try_take(&self) -> Result<(), SyscallError> {
+        self.take(NO_WAIT)
+    }
+
+    pub fn give(&self) -> Result<(), SyscallError> {
+        semaphore_give(self.handle)
+    }
+
+    /// Take a unit that is given back when the returned permit is dropped.
+    pub fn acquire(&self, timeout: u32) -> Result<SemaphorePermit<'_>, SyscallError> {
+        self.take(timeout)?;
+        Ok(SemaphorePermit(self))
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        if self.owned {
+            // Fails if tasks still wait for it, which keeps it alive for them.
+            let _ = semaphore_delete(self.handle);
+        }
+    }
+}
+
+/// Unit taken with [Semaphore::acquire].
+pub struct SemaphorePermit<'a>(&'a Semaphore);
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        self.0.give().expect("giving back semaphore failed");
+    }
+}
+
+/// Mutex shared between tasks.
+/// Tasks cannot share memory, so the mutex only guards access to other resources, e.g. a peripheral
+/// used through system calls.
+/// The mutex is deleted when the value returned by [Mutex::new] or [Mutex::with_ceiling] is dropped,
+/// so the creating task must keep it until the tasks it passed the handle to are done with it.
+#[derive(Debug)]
+pub struct Mutex {
+    handle: u32,
+    /// Whether this task created the mutex and deletes it when dropped.
+    owned: bool,
+}
+
+impl Mutex {
+    /// Create a mutex whose holder inherits the priority of the tasks waiting for it.
+    pub fn new() -> Result<Self, SyscallError> {
+        mutex_create(None).map(|handle| Self { handle, owned: true })
+    }
+
+    /// Create a mutex whose holder is raised to `ceiling` while it holds it. Tasks with a higher
+    /// priority may not lock it.
+    pub fn with_ceiling(ceiling: u8) -> Result<Self, SyscallError> {
+        mutex_create(Some(ceiling)).map(|handle| Self { handle, owned: true })
+    }
+
+    /// Mutex with a handle received from another task. It is not deleted when dropped.
+    pub fn from_handle(handle: u32) -> Self {
+        Self { handle, owned: false }
+    }
+
+    pub fn handle(&self) -> u32 {
+        self.handle
+    }
+
+    /// Lock the mutex, waiting up to `timeout` ticks for it, see [mutex_lock].
+    /// It is unlocked when the returned guard is dropped.
+    pub fn lock(&self, timeout: u32) -> Result<MutexGuard<'_>, SyscallError> {
+        mutex_lock(self.handle, timeout)?;
+        Ok(MutexGuard(self))
+    }
+
+    /// Lock the mutex if it is free without waiting.
+    pub fn try_lock(&self) -> Result<MutexGuard<'_>, SyscallError> {
+        self.lock(NO_WAIT)
+    }
+}
+
+/// Lock of a [Mutex], released when dropped.
+pub struct MutexGuard<'a>(&'a Mutex);
+
+impl Drop for MutexGuard<'_> {
+    fn drop(&mut self) {
+        mutex_unlock(self.0.handle).expect("unlocking mutex failed");
+    }
+}
+
+impl Drop for Mutex {
+    fn drop(&mut self) {
+        if self.owned {
+            // Fails if another task holds it, which keeps it alive for that task.
+            let _ = mutex_delete(self.handle);
+        }
+    }
+}
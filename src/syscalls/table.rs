@@ -0,0 +1,296 @@
+//! Declarative list of all system calls.
+//!
+//! Each entry defines:
+//! - the variant of [super::SyscallNumber] and the immediate of its `svc` instruction,
+//! - visibility, name, typed arguments and return value of the user-mode stub in [super::stubs],
+//! - the errors the call may return besides [super::SyscallError::NotImplemented] and
+//!   [super::SyscallError::InvalidArgument], which any call may return (used for documentation),
+//! - the number of state words the kernel keeps across restarts of the call (see
+//!   [super::kernel_mode::Call::restart]),
+//! - the kernel-side handler in [super::kernel_mode],
+//! - the [super::Abi] used to pass arguments and return values.
+//!
+//! Arguments and return values must implement [super::arguments::Argument].
+//! Adding a system call only requires a new entry here and its handler.
+
+/// Invokes `$generate!` with the list of system calls.
+macro_rules! syscall_table {
+    ($generate:ident) => {
+        $generate! {
+            /// Increment `value` by one and return it.
+            Increment = 0 {
+                pub fn increment(value: u32) -> u32;
+                errors: IncrementPastTen;
+                state: 0;
+                handler: handle_syscall_increment;
+                abi: Array;
+            }
+
+            /// Write `buffer` to USART2.
+            /// Returns number of bytes written (at most `buffer.len()`) or error.
+            Write = 1 {
+                pub fn write(buffer: &[u8]) -> usize;
+                errors: InsufficientSpace;
+                state: 0;
+                handler: handle_syscall_write;
+                abi: Array;
+            }
+
+            /// Read from USART2 into `buffer`.
+            /// Returns number of bytes read (at most `buffer.len()`), which is zero if nothing was received.
+            Read = 2 {
+                pub fn read(buffer: &mut [u8]) -> usize;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_read;
+                abi: Array;
+            }
+
+            /// Write all of `buffer` to USART2.
+            /// Unlike [write], the calling task is suspended by the kernel while the transmit buffer is full
+            /// instead of returning [SyscallError::InsufficientSpace].
+            /// Returns number of bytes written, which is always `buffer.len()` on success.
+            WriteBlocking = 3 {
+                pub fn write_blocking(buffer: &[u8]) -> usize;
+                errors: ;
+                state: 1;
+                handler: handle_syscall_write_blocking;
+                abi: Array;
+            }
+
+            /// Terminate the calling task with exit `code`, see [exit].
+            Exit = 4 {
+                fn exit_task(code: u32) -> ();
+                errors: ;
+                state: 0;
+                handler: handle_syscall_exit;
+                abi: Array;
+            }
+
+            /// Suspend the calling task for at least `ticks` kernel ticks.
+            Sleep = 5 {
+                pub fn sleep(ticks: u32) -> ();
+                errors: ;
+                state: 2;
+                handler: handle_syscall_sleep;
+                abi: Registers;
+            }
+
+            /// Give up the rest of the current time slice to other ready tasks.
+            Yield = 6 {
+                pub fn yield_now() -> ();
+                errors: ;
+                state: 0;
+                handler: handle_syscall_yield;
+                abi: Registers;
+            }
+
+            /// Start a new task with `stack_len` words of stack at `stack`, see [spawn].
+            /// The caller must be able to write to the stack, e.g. because it was granted to it.
+            /// `priority` may not exceed the caller's own priority.
+            Spawn = 7 {
+                fn spawn_task(entry: fn(usize), param: usize, stack: *mut u32, stack_len: usize, priority: u8) -> u32;
+                errors: InvalidStack, TaskTableFull;
+                state: 0;
+                handler: handle_syscall_spawn;
+                abi: Array;
+            }
+
+            /// Allow task `task` to access `memory` as well, e.g. a buffer shared with a worker.
+            /// The caller must have at least the requested access to `memory` itself.
+            /// Memory protection works on regions with a size that is a power of two of at least 32 bytes and
+            /// aligned to it, so `memory` must be such a region. Fails with [SyscallError::InvalidArgument]
+            /// otherwise.
+            Grant = 8 {
+                pub fn grant(task: u32, memory: &[u8], writable: bool) -> ();
+                errors: AccessDenied, NoSuchTask, TooManyRegions;
+                state: 0;
+                handler: handle_syscall_grant;
+                abi: Array;
+            }
+
+            /// Choose how the kernel treats unknown or malformed system calls of the calling task.
+            /// Tasks spawned afterwards inherit the policy.
+            SetFaultPolicy = 9 {
+                pub fn set_fault_policy(policy: FaultPolicy) -> ();
+                errors: ;
+                state: 0;
+                handler: handle_syscall_set_fault_policy;
+                abi: Array;
+            }
+
+            /// Number of kernel ticks since the scheduler was started.
+            GetTime = 10 {
+                pub fn get_time() -> u64;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_get_time;
+                abi: Registers;
+            }
+
+            /// Log calls to the kernel log if the kernel is built with the `syscall-trace` feature.
+            /// Only calls of `task` (or all with [super::TRACE_ALL_TASKS]) whose bit is set in `syscalls` are
+            /// traced, see [super::SyscallNumber::mask].
+            SetTrace = 11 {
+                pub fn set_trace(enabled: bool, task: u32, syscalls: u32) -> ();
+                errors: FeatureDisabled;
+                state: 0;
+                handler: handle_syscall_set_trace;
+                abi: Array;
+            }
+
+            /// Copy the text of the oldest kernel log record with a sequence number of at least `sequence`
+            /// into `buffer`, cutting it off if `buffer` is too short.
+            /// Returns `None` if there is no such record yet. Records overwritten before they were read
+            /// are skipped, which shows as a gap in the sequence numbers.
+            ReadLog = 12 {
+                pub fn read_log(sequence: u32, buffer: &mut [u8]) -> Option<LogRecord>;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_read_log;
+                abi: Array;
+            }
+
+            /// Discard kernel log records less important than `level` from now on.
+            SetLogLevel = 13 {
+                pub fn set_log_level(level: Level) -> ();
+                errors: ;
+                state: 0;
+                handler: handle_syscall_set_log_level;
+                abi: Array;
+            }
+
+            /// Copy the report of the crash that caused the last reset into `buffer`, cutting it off if
+            /// `buffer` is too short.
+            /// Returns the length of the report or `None` if the system was not reset by a crash.
+            ReadCrashReport = 14 {
+                pub fn read_crash_report(buffer: &mut [u8]) -> Option<usize>;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_read_crash_report;
+                abi: Array;
+            }
+
+            /// Describe the task in slot `index` of the task table, including its peak stack usage.
+            /// Returns `None` past the last slot, so all tasks can be listed by counting up from zero.
+            TaskInfo = 15 {
+                pub fn task_info(index: u32) -> Option<TaskInfo>;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_task_info;
+                abi: Array;
+            }
+
+            /// Processor usage of the task in slot `index` of the task table.
+            /// Returns `None` past the last slot, like `task_info`.
+            TaskStats = 16 {
+                pub fn task_stats(index: u32) -> Option<TaskStats>;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_task_stats;
+                abi: Array;
+            }
+
+            /// Call `hook` from the idle task whenever no other task is ready.
+            /// Hooks run unprivileged on the stack of the idle task, so they may only use data in flash
+            /// and system calls. They should return quickly and must not block.
+            /// A hook that ends the idle task, e.g. by faulting, is removed.
+            AddIdleHook = 17 {
+                pub fn add_idle_hook(hook: fn()) -> ();
+                errors: TooManyIdleHooks;
+                state: 0;
+                handler: handle_syscall_add_idle_hook;
+                abi: Registers;
+            }
+
+            /// Percentage of time spent outside the idle task during about the last second.
+            CpuLoad = 18 {
+                pub fn cpu_load() -> u32;
+                errors: ;
+                state: 0;
+                handler: handle_syscall_cpu_load;
+                abi: Registers;
+            }
+
+            /// Create a counting semaphore holding `initial` of at most `max` units.
+            /// Returns its handle, see [Semaphore] for a wrapper.
+            SemaphoreCreate = 19 {
+                pub fn semaphore_create(initial: u32, max: u32) -> u32;
+                errors: ObjectTableFull;
+                state: 0;
+                handler: handle_syscall_semaphore_create;
+                abi: Registers;
+            }
+
+            /// Take a unit of a semaphore, waiting up to `timeout` ticks for one to be given.
+            /// Pass [super::NO_WAIT] to fail immediately or [super::WAIT_FOREVER] to wait without timeout.
+            SemaphoreTake = 20 {
+                pub fn semaphore_take(handle: u32, timeout: u32) -> ();
+                errors: NoSuchObject, TimedOut;
+                state: 2;
+                handler: handle_syscall_semaphore_take;
+                abi: Registers;
+            }
+
+            /// Give a unit to a semaphore. The waiting task with the highest priority gets it directly.
+            SemaphoreGive = 21 {
+                pub fn semaphore_give(handle: u32) -> ();
+                errors: NoSuchObject, SemaphoreFull;
+                state: 0;
+                handler: handle_syscall_semaphore_give;
+                abi: Registers;
+            }
+
+            /// Create an unlocked mutex. Returns its handle, see [Mutex] for a wrapper.
+            /// The holder inherits the priority of the tasks waiting for the mutex. With a `ceiling`,
+            /// it is also raised to that priority while it holds the mutex, and tasks of a higher
+            /// priority may not lock it.
+            MutexCreate = 22 {
+                pub fn mutex_create(ceiling: Option<u8>) -> u32;
+                errors: ObjectTableFull;
+                state: 0;
+                handler: handle_syscall_mutex_create;
+                abi: Registers;
+            }
+
+            /// Lock a mutex, waiting up to `timeout` ticks for its holder to unlock it.
+            /// Pass [super::NO_WAIT] to fail immediately or [super::WAIT_FOREVER] to wait without timeout.
+            /// Mutexes held by a task are unlocked when it exits.
+            MutexLock = 23 {
+                pub fn mutex_lock(handle: u32, timeout: u32) -> ();
+                errors: NoSuchObject, TimedOut, WouldDeadlock, AboveCeiling;
+                state: 2;
+                handler: handle_syscall_mutex_lock;
+                abi: Registers;
+            }
+
+            /// Unlock a mutex held by the caller. The waiting task with the highest priority gets it
+            /// directly. The caller drops to the priority justified by the mutexes it still holds.
+            MutexUnlock = 24 {
+                pub fn mutex_unlock(handle: u32) -> ();
+                errors: NoSuchObject, NotOwner;
+                state: 0;
+                handler: handle_syscall_mutex_unlock;
+                abi: Registers;
+            }
+
+            /// Delete a semaphore nobody waits for. Its handle may be reused by a new semaphore.
+            SemaphoreDelete = 25 {
+                pub fn semaphore_delete(handle: u32) -> ();
+                errors: NoSuchObject, Busy;
+                state: 0;
+                handler: handle_syscall_semaphore_delete;
+                abi: Registers;
+            }
+
+            /// Delete an unlocked mutex. Its handle may be reused by a new mutex.
+            MutexDelete = 26 {
+                pub fn mutex_delete(handle: u32) -> ();
+                errors: NoSuchObject, Busy;
+                state: 0;
+                handler: handle_syscall_mutex_delete;
+                abi: Registers;
+            }
+        }
+    };
+}
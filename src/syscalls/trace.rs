@@ -0,0 +1,124 @@
+//! Tracing of system calls into the kernel log, built with the `syscall-trace` feature.
+//!
+//! Tracing is off until a task enables it with [super::stubs::set_trace]. Each traced call is logged
+//! as one line with the calling task, the call and its decoded arguments, the result and the
+//! number of cycles spent in the kernel, e.g.
+//! `task 1 Write(buffer=0x20010400+12) = Ok [412 cycles]`.
+
+use core::fmt::{self, Write};
+use crate::log::{self, Level, Record};
+use crate::task::TASK_TABLE;
+use crate::time;
+use super::{FaultPolicy, SyscallError, SyscallNumber, TRACE_ALL_SYSCALLS, TRACE_ALL_TASKS};
+
+/// Which calls are traced.
+struct Filter {
+    enabled: bool,
+    /// ID of the traced task or [TRACE_ALL_TASKS].
+    task: u32,
+    /// Mask of traced calls, see [SyscallNumber::mask].
+    syscalls: u32,
+}
+
+/// Call currently being traced.
+struct Trace {
+    record: Record,
+    /// Cycle count when the call was entered.
+    start: u32,
+    /// Cycles spent formatting arguments, which are not counted for the call.
+    overhead: u32,
+    arguments: usize,
+}
+
+static mut FILTER: Filter = Filter { enabled: false, task: TRACE_ALL_TASKS, syscalls: TRACE_ALL_SYSCALLS };
+static mut CURRENT: Option<Trace> = None;
+
+/// Replace the filter. Tracing stops immediately if `enabled` is false.
+pub(super) unsafe fn configure(enabled: bool, task: u32, syscalls: u32) {
+    FILTER = Filter { enabled, task, syscalls };
+}
+
+/// Start tracing a call of the current task if it passes the filter.
+/// `number` is `None` for unknown calls, which are only traced if all calls are.
+pub(super) unsafe fn begin(number: Option<SyscallNumber>) {
+    let start = time::cycles();
+    if !FILTER.enabled {
+        return;
+    }
+    let task = TASK_TABLE.current_task().id();
+    if FILTER.task != TRACE_ALL_TASKS && FILTER.task != task {
+        return;
+    }
+    let mask = number.map_or(TRACE_ALL_SYSCALLS, SyscallNumber::mask);
+    if FILTER.syscalls & mask != mask {
+        return;
+    }
+
+    let mut record = Record::EMPTY;
+    let _ = match number {
+        Some(number) => write!(record, "task {} {:?}(", task, number),
+        None => write!(record, "task {} unknown(", task),
+    };
+    CURRENT = Some(Trace { record, start, overhead: time::cycles().wrapping_sub(start), arguments: 0 });
+}
+
+/// Add a decoded argument to the traced call.
+pub(super) unsafe fn argument(name: &str, value: &impl Traced) {
+    let Some(trace) = CURRENT.as_mut() else {
+        return;
+    };
+    let start = time::cycles();
+    let separator = if trace.arguments > 0 { ", " } else { "" };
+    let _ = write!(trace.record, "{}{}=", separator, name);
+    let _ = value.trace(&mut trace.record);
+    trace.arguments += 1;
+    trace.overhead = trace.overhead.wrapping_add(time::cycles().wrapping_sub(start));
+}
+
+/// Log the traced call with its result. `result` holds whether the call completed.
+pub(super) unsafe fn end(result: &Result<bool, SyscallError>) {
+    let Some(mut trace) = CURRENT.take() else {
+        return;
+    };
+    let cycles = time::cycles().wrapping_sub(trace.start).wrapping_sub(trace.overhead);
+    let _ = match result {
+        Ok(true) => write!(trace.record, ") = Ok"),
+        Ok(false) => write!(trace.record, ") restarted"),
+        Err(e) => write!(trace.record, ") = {:?}", e),
+    };
+    let _ = write!(trace.record, " [{} cycles]", cycles);
+    // Tracing is switched on explicitly, so its records are kept whatever the log level.
+    log::push_unfiltered(Level::Info, trace.record);
+}
+
+/// Argument that can be shown in a trace.
+pub(super) trait Traced {
+    fn trace(&self, output: &mut dyn Write) -> fmt::Result;
+}
+
+macro_rules! traced_with_debug {
+    ($( $ty:ty ),*) => {
+        $(
+            impl Traced for $ty {
+                fn trace(&self, output: &mut dyn Write) -> fmt::Result {
+                    write!(output, "{:?}", self)
+                }
+            }
+        )*
+    };
+}
+
+traced_with_debug!(u8, u32, u64, usize, bool, FaultPolicy, Level, *mut u32, fn(usize), fn(), Option<u8>);
+
+/// Buffers are shown as address and length, since their contents may be long or not yet written.
+impl Traced for &[u8] {
+    fn trace(&self, output: &mut dyn Write) -> fmt::Result {
+        write!(output, "{:#x}+{}", self.as_ptr() as usize, self.len())
+    }
+}
+
+impl Traced for &mut [u8] {
+    fn trace(&self, output: &mut dyn Write) -> fmt::Result {
+        write!(output, "{:#x}+{}", self.as_ptr() as usize, self.len())
+    }
+}
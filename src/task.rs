@@ -1,18 +1,45 @@
 use core::ptr::{null_mut};
+use core::cmp::Reverse;
 use core::mem::MaybeUninit;
+use core::ops::Range;
 use core::sync::atomic::Ordering;
-use crate::{bios, global_peripherals};
-use cortex_m::register::control::{Fpca, Npriv, Spsel};
-use core::fmt::Write;
+use crate::{global_peripherals, idle, memory, mpu, sync, syscalls, time};
+use crate::mpu::{Access, MpuRegion, TASK_REGIONS};
+use crate::syscalls::FaultPolicy;
+use cortex_m::register::control::{Npriv, Spsel};
 
 pub(crate) const MAX_TASKS: usize = 8;
 
+/// Priority of the task started by [start_scheduler].
+/// Tasks with a higher priority value always preempt tasks with a lower value.
+pub(crate) const DEFAULT_PRIORITY: u8 = 1;
+
+/// Exit code of tasks ended because of an invalid system call.
+pub(crate) const EXIT_CODE_BAD_SYSCALL: u32 = 102;
+/// Exit code of tasks ended because of a MemManage, BusFault or UsageFault.
+pub(crate) const EXIT_CODE_FAULT: u32 = 103;
+/// Exit code of tasks ended because they overflowed their stack.
+pub(crate) const EXIT_CODE_STACK_OVERFLOW: u32 = 104;
+/// Written to the lowest word of every task stack. If it changes, the task has overflowed its stack.
+const STACK_CANARY: u32 = 0xbaad_f00d;
+/// Fills task stacks before they are used, so the peak usage can be found by scanning for it.
+const STACK_PAINT: u32 = 0xcccc_cccc;
+
+/// Smallest accepted task stack in words. Leaves room for the initial frame and a few calls.
+pub(crate) const MIN_STACK_SIZE: usize = 64;
+
 pub(crate) static mut TASK_TABLE: TaskTable = TaskTable::new();
 
 pub(crate) struct TaskTable {
     tasks: [MaybeUninit<Task>; MAX_TASKS],
     current: usize,
     size: usize,
+    /// ID assigned to the next inserted task.
+    next_id: u32,
+    /// Slot of the idle task, which only runs if no other task can.
+    idle: Option<usize>,
+    /// Ticket handed to the next blocked task, which orders waiting tasks of equal priority.
+    next_ticket: u32,
 }
 
 impl TaskTable {
@@ -23,57 +50,413 @@ impl TaskTable {
             tasks,
             current: 0,
             size: 0,
+            next_id: 0,
+            idle: None,
+            next_ticket: 0,
         }
     }
 
-    pub fn insert_task(&mut self, task: Task) {
-        unsafe { *self.tasks[self.size].assume_init_mut() = task };
-        self.size += 1;
+    /// Insert `task` into the first free slot, reusing slots of exited tasks.
+    /// Returns the ID assigned to the task or `None` if the table is full.
+    pub fn insert_task(&mut self, mut task: Task) -> Option<u32> {
+        let free = (0..self.size).find(|&index| Some(index) != self.idle && self.task(index).is_exited());
+        let index = match free {
+            Some(index) => index,
+            None if self.size < MAX_TASKS => {
+                self.size += 1;
+                self.size - 1
+            }
+            None => return None,
+        };
+        let id = self.next_id;
+        self.next_id += 1;
+        task.id = id;
+        self.tasks[index].write(task);
+        Some(id)
+    }
+
+    /// Find task with `id` that has not exited.
+    pub fn find_task(&mut self, id: u32) -> Option<&mut Task> {
+        (0..self.size)
+            .find(|&index| self.task(index).id == id && !self.task(index).is_exited())
+            .map(|index| self.task_mut(index))
+    }
+
+    /// Whether `region` overlaps the stack of any task that has not exited.
+    pub fn overlaps_stack(&self, region: &Range<usize>) -> bool {
+        (0..self.size)
+            .map(|index| self.task(index))
+            .any(|task| !task.is_exited() && memory::overlaps(&task.stack, region))
+    }
+
+    /// All occupied slots in table order, including exited tasks whose slot was not reused yet.
+    pub fn tasks(&self) -> impl Iterator<Item = &Task> {
+        (0..self.size).map(|index| self.task(index))
     }
 
     pub fn current_task(&mut self) -> &mut Task {
-        unsafe { self.tasks[self.current].assume_init_mut() }
+        self.task_mut(self.current)
     }
 
+    fn task(&self, index: usize) -> &Task {
+        unsafe { self.tasks[index].assume_init_ref() }
+    }
+
+    fn task_mut(&mut self, index: usize) -> &mut Task {
+        unsafe { self.tasks[index].assume_init_mut() }
+    }
+
+    /// Select the runnable task with the highest priority and mark it as running.
+    /// Tasks of equal priority are selected in round-robin order, so the current task is only
+    /// selected again if no other task of its priority is ready. The idle task is selected if no
+    /// other task is ready.
+    /// Returns `None` if no task can run.
     pub fn next_task(&mut self) -> Option<&mut Task> {
-        if self.size == 0 {
-            return None;
+        let candidate = |index: usize| Some(index) != self.idle && self.task(index).is_runnable();
+        let priority = (0..self.size)
+            .filter(|&index| candidate(index))
+            .map(|index| self.task(index).priority)
+            .max();
+        let next = match priority {
+            Some(priority) => (1..=self.size)
+                .map(|offset| (self.current + offset) % self.size)
+                .find(|&index| candidate(index) && self.task(index).priority == priority)?,
+            None => self.idle.filter(|&index| self.task(index).is_runnable())?,
+        };
+
+        let current = self.current_task();
+        if current.state == TaskState::Running {
+            current.state = TaskState::Ready;
+        }
+        self.current = next;
+        let task = self.current_task();
+        task.state = TaskState::Running;
+        Some(task)
+    }
+
+    /// Earliest tick a sleeping task wakes up at, or `None` if no task is sleeping.
+    pub fn next_wake_up(&self) -> Option<u64> {
+        self.tasks()
+            .filter_map(|task| match task.state {
+                TaskState::Sleeping(until) | TaskState::Blocked(_, Some(until)) => Some(until),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Make the task with `id` the idle task.
+    pub fn set_idle_task(&mut self, id: u32) {
+        self.idle = (0..self.size).find(|&index| self.task(index).id == id);
+    }
+
+    pub fn idle_task(&self) -> Option<&Task> {
+        self.idle.map(|index| self.task(index))
+    }
+
+    /// Whether `task` is the idle task.
+    pub fn is_idle_task(&self, task: *const Task) -> bool {
+        self.idle_task().is_some_and(|idle| core::ptr::eq(idle, task))
+    }
+
+    /// Make all tasks waiting for `reason` ready again.
+    /// Returns the highest priority of all woken tasks or `None` if no task was woken.
+    pub fn wake(&mut self, reason: WaitReason) -> Option<u8> {
+        let mut woken = None;
+        for index in 0..self.size {
+            let task = self.task_mut(index);
+            if matches!(task.state, TaskState::Blocked(waiting, _) if waiting == reason) {
+                task.state = TaskState::Ready;
+                woken = woken.max(Some(task.priority));
+            }
         }
-        self.current = (self.current + 1) % self.size;
-        unsafe { Some(self.tasks[self.current].assume_init_mut()) }
+        woken
     }
+
+    /// Hand the object `reason` stands for to the task waiting for it with the highest priority,
+    /// preferring the one that has waited longest. The task becomes ready and is marked as signalled,
+    /// so its restarted system call knows it got the object.
+    /// Returns `None` if no task is waiting for `reason`.
+    pub fn signal(&mut self, reason: WaitReason) -> Option<&mut Task> {
+        let index = (0..self.size)
+            .filter(|&index| matches!(self.task(index).state, TaskState::Blocked(waiting, _) if waiting == reason))
+            .max_by_key(|&index| {
+                let task = self.task(index);
+                (task.priority, Reverse(task.wait_ticket))
+            })?;
+        let task = self.task_mut(index);
+        task.state = TaskState::Ready;
+        task.signalled = true;
+        Some(task)
+    }
+
+    /// Highest priority of the tasks waiting for `reason`, or `None` if no task is waiting.
+    pub fn highest_waiting_priority(&self, reason: WaitReason) -> Option<u8> {
+        self.tasks()
+            .filter(|task| task.waiting_for() == Some(reason))
+            .map(|task| task.priority)
+            .max()
+    }
+
+    /// Make all tasks whose sleep or wait ends at or before tick `now` ready again.
+    /// Returns the highest priority of all woken tasks or `None` if no task was woken.
+    pub fn wake_sleeping(&mut self, now: u64) -> Option<u8> {
+        let mut woken = None;
+        for index in 0..self.size {
+            let task = self.task_mut(index);
+            if let TaskState::Sleeping(until) | TaskState::Blocked(_, Some(until)) = task.state {
+                if until <= now {
+                    task.state = TaskState::Ready;
+                    woken = woken.max(Some(task.priority));
+                }
+            }
+        }
+        woken
+    }
+}
+
+/// Condition a blocked task is waiting for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WaitReason {
+    /// Free space in the BIOS transmit buffer.
+    TxSpace,
+    /// A unit of the semaphore with the given handle.
+    Semaphore(u32),
+    /// The mutex with the given handle.
+    Mutex(u32),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TaskState {
+    /// Task may be scheduled.
+    Ready,
+    /// Task is currently executing.
+    Running,
+    /// Task is not scheduled until it is woken for the given reason or the optional tick has passed.
+    Blocked(WaitReason, Option<u64>),
+    /// Task is not scheduled until the given tick.
+    Sleeping(u64),
+    /// Task has finished with the given exit code. Its slot may be reused.
+    Exited(u32),
+}
+
+/// EXC_RETURN for returning to thread mode on the process stack without floating point context.
+const EXC_RETURN_THREAD_PSP: u32 = 0xFFFFFFFD;
+
+/// Note: [crate::dispatcher::PendSV] expects `stack_pointer` and `exc_return` to be the first
+/// fields.
 #[repr(C)]
 pub(crate) struct Task {
     stack_pointer: *mut u32,
+    /// EXC_RETURN value the task was interrupted with. Tells whether s16-s31 were saved on its stack.
+    exc_return: u32,
+    id: u32,
+    state: TaskState,
+    /// Priority used for scheduling. Raised above `base_priority` while the task holds a mutex
+    /// another task with a higher priority waits for, or a mutex with a higher ceiling.
+    priority: u8,
+    /// Priority the task was created with.
+    base_priority: u8,
+    /// Addresses of the memory reserved for the task's stack.
+    stack: Range<usize>,
+    /// Memory the task may access in unprivileged mode. The first region covers its stack.
+    regions: [Option<MpuRegion>; TASK_REGIONS],
+    /// What happens when the task makes an invalid system call.
+    fault_policy: FaultPolicy,
+    /// Cycles the task has been running for, including exceptions taken while it was running.
+    cycles: u64,
+    /// Number of times the task was switched to.
+    switches: u32,
+    /// Number of times the task was switched away from although it could have continued.
+    preemptions: u32,
+    /// Whether the task gave up the processor itself since it was last switched to, so switching
+    /// away from it is no preemption.
+    yielded: bool,
+    /// Whether the object the task was blocked on was handed to it, see [TaskTable::signal].
+    signalled: bool,
+    /// Order in which the task was blocked, see [TaskTable::next_ticket].
+    wait_ticket: u32,
 }
 
 impl Task {
-    /// Create a new dummy Task.
+    /// Create a new dummy Task running on `stack`.
     /// Stack pointer is invalid and is assumed to be overwritten before first switch to this Task.
-    fn new_dummy() -> Self {
-        Self {
-            stack_pointer: null_mut()
+    /// Returns `None` if no region can cover exactly the stack, see [Self::stack_regions].
+    fn new_dummy(stack: Range<usize>) -> Option<Self> {
+        Some(Self {
+            stack_pointer: null_mut(),
+            exc_return: EXC_RETURN_THREAD_PSP,
+            id: 0,
+            state: TaskState::Ready,
+            priority: DEFAULT_PRIORITY,
+            base_priority: DEFAULT_PRIORITY,
+            regions: Self::stack_regions(&stack)?,
+            fault_policy: FaultPolicy::ReturnError,
+            cycles: 0,
+            switches: 0,
+            preemptions: 0,
+            yielded: false,
+            signalled: false,
+            wait_ticket: 0,
+            stack,
+        })
+    }
+
+    /// Create a new Task with a given stack pointer and priority.
+    /// Returns `None` if no region can cover exactly the stack, see [Self::stack_regions].
+    fn new(stack_pointer: *mut u32, stack: Range<usize>, priority: u8) -> Option<Self> {
+        Some(Self {
+            stack_pointer,
+            exc_return: EXC_RETURN_THREAD_PSP,
+            id: 0,
+            state: TaskState::Ready,
+            priority,
+            base_priority: priority,
+            regions: Self::stack_regions(&stack)?,
+            fault_policy: FaultPolicy::ReturnError,
+            cycles: 0,
+            switches: 0,
+            preemptions: 0,
+            yielded: false,
+            signalled: false,
+            wait_ticket: 0,
+            stack,
+        })
+    }
+
+    /// Initial regions of a task, only granting access to its stack. The stack must be a power of two
+    /// of at least 32 bytes and aligned to its size, so the region covers nothing else.
+    fn stack_regions(stack: &Range<usize>) -> Option<[Option<MpuRegion>; TASK_REGIONS]> {
+        let mut regions = [None; TASK_REGIONS];
+        regions[0] = Some(MpuRegion::covering(stack, Access::ReadWrite)?);
+        Some(regions)
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn base_priority(&self) -> u8 {
+        self.base_priority
+    }
+
+    /// Change the priority used for scheduling, see [crate::sync] for priority inheritance.
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    /// Condition the task is blocked on, if any.
+    pub fn waiting_for(&self) -> Option<WaitReason> {
+        match self.state {
+            TaskState::Blocked(reason, _) => Some(reason),
+            _ => None,
         }
     }
 
-    /// Create a new Task with a given stack pointer.
-    fn new(stack: *mut u32) -> Self {
-        Self {
-            stack_pointer: stack
+    /// Exit code if the task has finished.
+    pub fn exit_code(&self) -> Option<u32> {
+        match self.state {
+            TaskState::Exited(code) => Some(code),
+            _ => None,
         }
     }
+
+    /// Addresses of the memory reserved for the task's stack.
+    pub fn stack(&self) -> &Range<usize> {
+        &self.stack
+    }
+
+    /// Cycles the task has been running for.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Number of times the task was switched to.
+    pub fn switches(&self) -> u32 {
+        self.switches
+    }
+
+    /// Number of times the task was switched away from although it could have continued.
+    pub fn preemptions(&self) -> u32 {
+        self.preemptions
+    }
+
+    /// Most bytes of its stack the task has used so far, found by scanning upwards from the canary
+    /// for the first word that no longer holds [STACK_PAINT].
+    pub fn stack_peak(&self) -> usize {
+        let lowest_used = (self.stack.start + 4..self.stack.end)
+            .step_by(4)
+            .find(|&address| unsafe { *(address as *const u32) } != STACK_PAINT)
+            .unwrap_or(self.stack.end);
+        self.stack.end - lowest_used
+    }
+
+    /// Whether the task has grown its stack beyond the bottom, judging by its saved stack pointer and
+    /// the canary. Only meaningful while the task is switched out.
+    /// A stack pointer above the stack is treated the same, since the task's context is lost then, too.
+    pub fn has_overflowed(&self) -> bool {
+        let canary = unsafe { *(self.stack.start as *const u32) };
+        let stack_pointer = self.stack_pointer as usize;
+        stack_pointer < self.stack.start + 4 || stack_pointer > self.stack.end || canary != STACK_CANARY
+    }
+
+    pub fn fault_policy(&self) -> FaultPolicy {
+        self.fault_policy
+    }
+
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy) {
+        self.fault_policy = policy;
+    }
+
+    pub fn regions(&self) -> &[Option<MpuRegion>; TASK_REGIONS] {
+        &self.regions
+    }
+
+    /// Whether the task may access all of `memory` in unprivileged mode.
+    pub fn can_access(&self, memory: &Range<usize>, access: Access) -> bool {
+        crate::mpu::can_access(&self.regions, memory, access)
+    }
+
+    /// Add `region` to the memory the task may access.
+    /// Returns `false` if all regions of the task are in use.
+    pub fn add_region(&mut self, region: MpuRegion) -> bool {
+        match self.regions.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(region);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the task may be selected by the scheduler.
+    pub fn is_runnable(&self) -> bool {
+        matches!(self.state, TaskState::Ready | TaskState::Running)
+    }
+
+    pub fn is_exited(&self) -> bool {
+        matches!(self.state, TaskState::Exited(_))
+    }
 }
 
 pub(crate) static mut OS_CURRENT_TASK: *mut Task = core::ptr::null_mut();
 pub(crate) static mut OS_NEXT_TASK: *mut Task = core::ptr::null_mut();
+/// Cycle count when the running task was last charged for its time, see [account_switch].
+static mut ACCOUNTED_AT: u32 = 0;
 
 /// Hand off control to the scheduler.
 /// Sets up process stack to use provided stack, switches to unprivileged thread mode and starts
-/// execution of `entry`.
-pub(crate) fn start_scheduler(app_stack: &mut [u32], entry: impl FnOnce() -> !) -> ! {
-    initialize_scheduler();
+/// execution of `entry`. The application task may also read and write `worker_memory`, e.g. to
+/// spawn workers on stacks placed there.
+pub(crate) fn start_scheduler(app_stack: &mut [u32], worker_memory: Range<usize>, entry: impl FnOnce() -> !) -> ! {
+    let range = app_stack.as_mut_ptr_range();
+    /// We are still running on the main stack, so the whole application stack can be painted.
+    app_stack.fill(STACK_PAINT);
+    app_stack[0] = STACK_CANARY;
+    initialize_scheduler(range.start as usize..range.end as usize, worker_memory);
 
     /// Setup process stack before switching to it.
     /// Hopefully, we can avoid disabling interrupts for this.
@@ -81,11 +464,26 @@ pub(crate) fn start_scheduler(app_stack: &mut [u32], entry: impl FnOnce() -> !)
     top = top - top % 8;
     unsafe { cortex_m::register::psp::write(top) }
 
-    /// Switch to unprivileged thread mode without floating point.
-    let mut control = cortex_m::register::control::read();
-    if control.fpca() != Fpca::NotActive {
-        todo!("floating point mode is not supported yet")
+    /// The dispatcher loads the regions of a task when switching to it, but the application task is
+    /// entered directly, so it needs its regions before it can use its stack in unprivileged mode.
+    unsafe {
+        let app_task = TASK_TABLE.current_task();
+        mpu::load_task_regions(app_task.regions());
+        #[cfg(feature = "stack-guard")]
+        mpu::load_stack_guard(app_task.stack());
     }
+    cortex_m::asm::dsb();
+
+    /// Ticks may only start once there is a valid process stack for PendSV to switch from.
+    /// The application task is charged for its time from here on.
+    unsafe {
+        ACCOUNTED_AT = time::cycles();
+        time::enable_tick()
+    }
+
+    /// Switch to unprivileged thread mode. An active floating point context (FPCA) is kept, it is
+    /// saved by the dispatcher like that of any other task.
+    let mut control = cortex_m::register::control::read();
     control.set_spsel(Spsel::Psp);
     control.set_npriv(Npriv::Unprivileged);
     /// Note: [cortex_m::register::control::write] accesses stack around asm, which will not work
@@ -108,33 +506,71 @@ pub(crate) fn start_scheduler(app_stack: &mut [u32], entry: impl FnOnce() -> !)
 }
 
 /// Initialize scheduler structures with dummy data to allow a context switch.
-fn initialize_scheduler() {
+fn initialize_scheduler(app_stack: Range<usize>, worker_memory: Range<usize>) {
     /// Setup task table using a dummy task. At least one task is required for a context switch to
     /// work, since the stack pointer is written/read to/from the last/next task.
-    let app_task = Task::new_dummy();
+    let mut app_task = Task::new_dummy(app_stack).expect("application stack cannot be covered by a region");
+    if !worker_memory.is_empty() {
+        let region = MpuRegion::covering(&worker_memory, Access::ReadWrite).expect("worker memory cannot be covered by a region");
+        assert!(app_task.add_region(region), "no region left for worker memory");
+    }
     unsafe {
-        TASK_TABLE.insert_task(app_task);
+        TASK_TABLE.insert_task(app_task).expect("failed to insert dummy task");
         OS_NEXT_TASK = TASK_TABLE.next_task().expect("failed to initialize dummy task");
         // Required to have a valid reference during first scheduler run.
         OS_CURRENT_TASK = OS_NEXT_TASK;
+        idle::create_idle_task();
     }
 
-    let mut output = bios::buffered_output();
-    writeln!(output, "Started scheduler!").unwrap();
+    log!(Info, "Started scheduler!");
 }
 
 
+/// Select the task [crate::dispatcher::PendSV] switches to next.
+/// If no other task is ready, the current task keeps running.
 pub(crate) fn schedule_next_task() {
-    unsafe { OS_CURRENT_TASK = OS_NEXT_TASK; }
-    unsafe { OS_NEXT_TASK = TASK_TABLE.next_task().expect("failed to get next task") };
+    if let Some(task) = unsafe { TASK_TABLE.next_task() } {
+        unsafe { OS_NEXT_TASK = task };
+    }
+
+    log!(Trace, "scheduled task {}", unsafe { (*OS_NEXT_TASK).id() });
+}
+
+/// Create a new task running `handler` on `stack` with the given `priority`.
+/// `param` is passed to `handler` in R0, so one handler may be started several times with
+/// different arguments.
+/// The task is only scheduled once the scheduler runs next, see [reschedule].
+/// Returns the ID of the new task or `None` if the task table is full or the stack is not sized and
+/// aligned as required by [Task::stack_regions].
+pub(crate) fn create_task(handler: fn(usize), param: usize, stack: &mut [u32], priority: u8) -> Option<u32> {
+    let range = stack.as_mut_ptr_range();
+    let bounds = range.start as usize..range.end as usize;
+    let top = prepare_stack(handler, param, stack);
+    let task = Task::new(top, bounds, priority)?;
+    unsafe { TASK_TABLE.insert_task(task) }
+}
 
-    let mut output = bios::buffered_output();
-    writeln!(output, "scheduled task {:?}", unsafe { OS_NEXT_TASK }).unwrap();
+/// Start `task` over by running `handler` on its stack again, e.g. after it exited.
+/// Its ID, regions and statistics are kept.
+pub(crate) unsafe fn restart_task(task: &mut Task, handler: fn(usize), param: usize) {
+    let stack = core::slice::from_raw_parts_mut(task.stack.start as *mut u32, task.stack.len() / 4);
+    task.stack_pointer = prepare_stack(handler, param, stack);
+    task.exc_return = EXC_RETURN_THREAD_PSP;
+    task.state = TaskState::Ready;
+    task.priority = task.base_priority;
+    task.yielded = false;
+    task.signalled = false;
 }
 
-pub(crate) fn create_task(handler: fn() -> (), _params: *const (), stack: &mut [u32]) {
-    // Stacks grow down, so we take the pointer just past the end
-    let mut top = stack.as_mut_ptr_range().end;
+/// Paint `stack` and push the initial frame for running `handler(param)` onto it, which
+/// [crate::dispatcher::PendSV] restores when switching to the task for the first time.
+/// Returns the initial stack pointer.
+fn prepare_stack(handler: fn(usize), param: usize, stack: &mut [u32]) -> *mut u32 {
+    stack.fill(STACK_PAINT);
+    stack[0] = STACK_CANARY;
+    // Stacks grow down, so we take the pointer just past the end, aligned to 8 bytes for the
+    // exception frame.
+    let mut top = (stack.as_mut_ptr_range().end as usize & !0x7) as *mut u32;
 
     macro_rules! push {
         ($value:expr) => {unsafe{top = top.wrapping_sub(1); *top = $value;}};
@@ -151,9 +587,9 @@ pub(crate) fn create_task(handler: fn() -> (), _params: *const (), stack: &mut [
     push!(3);
     push!(2);
     push!(1);
-    push!(0);
-    // R4-R11 + LR (popped in reverse)
-    // push!(0xFFFFFFF9u32);
+    push!(param as u32);
+    // R4-R11 (popped in reverse). The task starts with a basic frame, since EXC_RETURN in its task
+    // control block indicates no floating point context.
     push!(11);
     push!(10);
     push!(9);
@@ -162,13 +598,159 @@ pub(crate) fn create_task(handler: fn() -> (), _params: *const (), stack: &mut [
     push!(6);
     push!(5);
     push!(4);
+    top
+}
 
-    let task = Task::new(top);
-    unsafe { TASK_TABLE.insert_task(task); }
+/// Check whether a stack of `len` words at `start` may be used for a new task created by the current
+/// task. It must hold at least [MIN_STACK_SIZE] words, lie within task RAM and not be used by another
+/// task. Its size must be a power of two it is aligned to, so a region can cover exactly the stack.
+/// The new task gets read-write access to that region, so the current task must be able to write
+/// to it itself.
+pub(crate) fn validate_stack(start: usize, len: usize) -> bool {
+    let region = start..start.saturating_add(len.saturating_mul(4));
+    len >= MIN_STACK_SIZE
+        && memory::contains(&memory::app_ram(), &region)
+        && !unsafe { TASK_TABLE.overlaps_stack(&region) }
+        && MpuRegion::covering(&region, Access::ReadWrite).is_some_and(|stack| {
+            unsafe { TASK_TABLE.current_task() }.can_access(&stack.memory(), Access::ReadWrite)
+        })
 }
 
-fn task_finished() {
-    loop {
-        cortex_m::asm::bkpt();
+/// Select the next task and request a context switch to it.
+pub(crate) fn reschedule() {
+    schedule_next_task();
+    cortex_m::peripheral::SCB::set_pendsv();
+}
+
+/// Block the current task until it is woken for `reason` or tick `deadline` has passed, and request
+/// a context switch.
+pub(crate) fn block_current_task(reason: WaitReason, deadline: Option<u64>) {
+    unsafe {
+        let ticket = TASK_TABLE.next_ticket;
+        TASK_TABLE.next_ticket = ticket.wrapping_add(1);
+        let task = TASK_TABLE.current_task();
+        task.state = TaskState::Blocked(reason, deadline);
+        task.signalled = false;
+        task.wait_ticket = ticket;
+    }
+    reschedule();
+}
+
+/// Whether the current task was handed the object it was blocked on. Clears the mark, so it is only
+/// seen by the first restart of the blocked call.
+pub(crate) fn take_signal() -> bool {
+    unsafe { core::mem::take(&mut TASK_TABLE.current_task().signalled) }
+}
+
+/// Let other ready tasks of the current task's priority run and request a context switch.
+pub(crate) fn yield_current_task() {
+    unsafe { TASK_TABLE.current_task().yielded = true };
+    reschedule();
+}
+
+/// Put the current task to sleep until tick `until` and request a context switch.
+pub(crate) fn sleep_current_task(until: u64) {
+    unsafe { TASK_TABLE.current_task().state = TaskState::Sleeping(until) };
+    reschedule();
+}
+
+/// Make all tasks whose sleep has ended ready again.
+/// Only called from the SysTick exception, which reschedules anyway.
+pub(crate) fn wake_sleeping_tasks(now: u64) {
+    unsafe { TASK_TABLE.wake_sleeping(now); }
+}
+
+/// Mark the current task as exited with `code`, freeing its slot and its mutexes, and request a
+/// context switch.
+pub(crate) fn exit_current_task(code: u32) {
+    unsafe { end_task(TASK_TABLE.current_task(), code) };
+    reschedule();
+}
+
+/// Mark `task` as exited with `code` and release its mutexes. A task may exit while it is blocked,
+/// e.g. if it overflowed its stack in a blocking call. If it was waiting for a mutex, the holder loses
+/// the priority it inherited from `task`.
+unsafe fn end_task(task: &mut Task, code: u32) {
+    let waiting_for = task.waiting_for();
+    task.state = TaskState::Exited(code);
+    sync::release_mutexes(task.id);
+    if let Some(WaitReason::Mutex(handle)) = waiting_for {
+        sync::update_holder_priority(handle);
+    }
+}
+
+/// Charge the cycles since the last switch to `previous` and count the switch to `next`.
+/// Called by the dispatcher on every context switch, which happens on every SysTick exception, so
+/// the cycle counter cannot wrap around unnoticed even while ticks are suppressed.
+pub(crate) unsafe fn account_switch(previous: *mut Task, next: *mut Task) {
+    let now = time::cycles();
+    (*previous).cycles += now.wrapping_sub(ACCOUNTED_AT) as u64;
+    ACCOUNTED_AT = now;
+    let yielded = core::mem::take(&mut (*previous).yielded);
+    if previous == next {
+        return;
+    }
+    (*next).switches += 1;
+    // The scheduler puts the running task back to ready if it selects another one.
+    if (*previous).state == TaskState::Ready && !yielded {
+        (*previous).preemptions += 1;
     }
+}
+
+/// End `task`, which has overflowed its stack. Called by the dispatcher when switching away from it.
+pub(crate) unsafe fn handle_stack_overflow(task: *mut Task) {
+    let overflowed = &mut *task;
+    log!(
+        Error,
+        "Stack overflow in task {}: stack {:#010x}..{:#010x}, sp={:#010x}",
+        overflowed.id,
+        overflowed.stack.start,
+        overflowed.stack.end,
+        overflowed.stack_pointer as usize
+    );
+    end_task(overflowed, EXIT_CODE_STACK_OVERFLOW);
+}
+
+/// Select a task to switch to instead of `next` if `next` may not run anymore, e.g. because it
+/// overflowed its stack or exited while no other task was ready, and make it the current task.
+/// Called by the dispatcher after it has started the idle task over if it exited, so there is always
+/// a task to select.
+pub(crate) unsafe fn replace_next_task(next: *mut Task) -> *mut Task {
+    if (*next).state == TaskState::Running {
+        return next;
+    }
+    let replacement: *mut Task = TASK_TABLE.next_task().expect("idle task is always ready");
+    OS_CURRENT_TASK = replacement;
+    OS_NEXT_TASK = replacement;
+    replacement
+}
+
+/// Make all tasks waiting for `reason` ready again.
+/// The current task is preempted immediately if a woken task has a higher priority.
+pub(crate) fn wake_tasks(reason: WaitReason) {
+    unsafe {
+        if let Some(priority) = TASK_TABLE.wake(reason) {
+            if priority > TASK_TABLE.current_task().priority {
+                reschedule();
+            }
+        }
+    }
+}
+
+/// Hand the object `reason` stands for to the task waiting for it, see [TaskTable::signal].
+/// The current task is preempted immediately if the woken task has a higher priority.
+/// Returns the ID of the woken task or `None` if no task is waiting.
+pub(crate) fn signal_task(reason: WaitReason) -> Option<u32> {
+    unsafe {
+        let (id, priority) = TASK_TABLE.signal(reason).map(|task| (task.id, task.priority))?;
+        if priority > TASK_TABLE.current_task().priority {
+            reschedule();
+        }
+        Some(id)
+    }
+}
+
+/// Return address of every task. Runs in unprivileged mode once the task handler returns.
+fn task_finished() {
+    syscalls::stubs::exit(0)
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+//! Kernel time keeping using the SysTick timer.
+
+use cortex_m::peripheral::{DCB, DWT, SCB, SYST};
+use cortex_m::peripheral::syst::SystClkSource;
+use stm32f4xx_hal::rcc::Clocks;
+
+/// Frequency of kernel ticks in Hz.
+pub const TICK_HZ: u32 = 100;
+
+/// Largest value of the 24 bit SysTick reload register.
+const MAX_RELOAD: u32 = 0x00ff_ffff;
+/// Bits of the SysTick control and status register.
+const CSR_TICKINT: u32 = 1 << 1;
+const CSR_CLKSOURCE: u32 = 1 << 2;
+/// Set when the counter wrapped since the register was last read. Reading clears it.
+const CSR_COUNTFLAG: u32 = 1 << 16;
+
+static mut TIMER: Option<SYST> = None;
+/// Number of ticks since the tick timer was enabled.
+static mut TICKS: u64 = 0;
+/// Timer cycles per tick.
+static mut PERIOD: u32 = 0;
+/// Timer cycles of the current tick that had passed when ticks were suppressed, `None` while
+/// ticking periodically.
+static mut SUPPRESSED: Option<u32> = None;
+
+/// Configure SysTick to fire every tick. The timer is not started until [enable_tick].
+pub fn configure_tick(mut syst: SYST, clocks: &Clocks) {
+    syst.disable_counter();
+    syst.set_clock_source(SystClkSource::Core);
+    let period = clocks.sysclk().raw() / TICK_HZ;
+    syst.set_reload(period - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    unsafe {
+        PERIOD = period;
+        TIMER = Some(syst);
+    }
+}
+
+/// Start generating ticks.
+pub unsafe fn enable_tick() {
+    if let Some(syst) = TIMER.as_mut() {
+        syst.enable_counter();
+    }
+}
+
+/// Advance tick counter by one, or by the number of ticks that passed while ticks were suppressed.
+/// Only called from the SysTick exception.
+pub unsafe fn tick() {
+    if SUPPRESSED.is_some() {
+        resume_ticks();
+    } else {
+        TICKS += 1;
+    }
+}
+
+/// Let the next SysTick exception fire after `ticks` ticks instead of the next one, so the
+/// processor is not woken up for nothing while idle. The current tick counts as the first one.
+/// The delay is limited by the 24 bit reload register, e.g. to about 2 s at 8 MHz.
+/// Only called from exceptions of kernel priority.
+pub unsafe fn suppress_ticks(ticks: u64) {
+    let Some(syst) = TIMER.as_mut() else {
+        return;
+    };
+    let ticks = ticks.min(((MAX_RELOAD as u64 + 1) / PERIOD as u64).max(1)) as u32;
+    if ticks <= 1 || SUPPRESSED.is_some() {
+        return;
+    }
+    // The few cycles the counter is stopped for are lost, so time drifts slightly while idle.
+    syst.disable_counter();
+    let passed = PERIOD - 1 - SYST::get_current();
+    syst.set_reload(ticks * PERIOD - passed - 1);
+    syst.clear_current();
+    syst.enable_counter();
+    SUPPRESSED = Some(passed);
+}
+
+/// End suppression of ticks early, e.g. because a task became ready.
+/// The SysTick exception is requested immediately and corrects the tick count.
+pub fn wake_from_idle() {
+    if unsafe { SUPPRESSED.is_some() } {
+        SCB::set_pendst();
+    }
+}
+
+/// Add the ticks that passed since [suppress_ticks] and return to periodic ticks, keeping the
+/// phase of the tick within the elapsed time.
+unsafe fn resume_ticks() {
+    let (Some(syst), Some(passed)) = (TIMER.as_mut(), SUPPRESSED.take()) else {
+        return;
+    };
+    // Stop the counter with a plain write like FreeRTOS does. Disabling it with a read-modify-write
+    // would clear COUNTFLAG before we can check it, losing the wrap that ended the suppression.
+    let csr = &(*SYST::PTR).csr;
+    csr.write(CSR_CLKSOURCE | CSR_TICKINT);
+    let wrapped = csr.read() & CSR_COUNTFLAG != 0;
+    let reload = SYST::get_reload();
+    // The counter counts down from `reload`, and starts over from it after wrapping at zero.
+    let mut elapsed = passed + reload - SYST::get_current();
+    if wrapped {
+        elapsed += reload + 1;
+        // If we were woken early and the counter wrapped meanwhile, the wrap requested another
+        // SysTick exception, which would count a tick twice.
+        SCB::clear_pendst();
+    }
+    TICKS += (elapsed / PERIOD) as u64;
+
+    // Like FreeRTOS, start with the rest of the current tick and restore the full period for the
+    // following ones, which is only loaded after the first wrap.
+    // A reload value of zero would stop the timer.
+    let remaining = PERIOD - elapsed % PERIOD;
+    syst.set_reload(remaining.max(2) - 1);
+    syst.clear_current();
+    syst.enable_counter();
+    syst.set_reload(PERIOD - 1);
+}
+
+/// Current tick count.
+pub fn now() -> u64 {
+    unsafe { TICKS }
+}
+
+/// Start the cycle counter of the DWT, used to measure short durations.
+pub fn enable_cycle_counter(dcb: &mut DCB, dwt: &mut DWT) {
+    dcb.enable_trace();
+    DWT::unlock();
+    dwt.enable_cycle_counter();
+}
+
+/// Current value of the cycle counter. Wraps around after 2^32 cycles.
+pub fn cycles() -> u32 {
+    DWT::cycle_count()
+}